@@ -6,22 +6,43 @@ use serde::Serialize;
 use std::env;
 use std::fmt;
 
+use crate::commons::encoder::EncoderOptions;
+use crate::commons::{ImageFormat, KeepMetadata};
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Configuration {
     pub app_port: u16,
     pub health_port: u16,
     pub log_level: Option<String>,
     pub vips_threads: Option<u16>,
+    pub processing_threads: Option<u16>,
     pub reqwest_timeout_millis: Option<u16>,
     pub reqwest_connection_timeout_millis: Option<u16>,
     pub reqwest_pool_max_idle_per_host: Option<u16>,
     pub reqwest_pool_idle_timeout_millis: Option<u16>,
+    pub request_deadline_millis: Option<u32>,
+    pub max_processing_jobs: Option<u32>,
+    pub max_queued_processing_jobs: Option<u32>,
+    pub processing_memory_budget: Option<u64>,
     pub s3_region: Option<String>,
     pub s3_key: Option<String>,
     pub s3_secret: Option<String>,
     pub s3_endpoint: Option<String>,
     pub s3_bucket: Option<String>,
     pub max_file_size: Option<u32>,
+    pub max_input_pixels: Option<u64>,
+    pub max_input_dimension: Option<u32>,
+    pub max_output_pixels: Option<u64>,
+    pub pdf_loader_enabled: Option<bool>,
+    pub auto_formats: Option<Vec<ImageFormat>>,
+    pub encoder_defaults: Option<EncoderOptions>,
+    pub max_bytes_min_quality: Option<i32>,
+    pub max_bytes_min_scale: Option<f64>,
+    pub keep_metadata: Option<KeepMetadata>,
+    pub cmyk_fallback_profile: Option<String>,
+    pub passthrough_enabled: Option<bool>,
+    pub never_larger_than_source: Option<bool>,
+    pub shrink_on_load_enabled: Option<bool>,
     pub watermark_cache_size: Option<u64>,
     pub watermark_cache_ttl_seconds: Option<u64>,
     pub otel_collector_endpoint: Option<String>,
@@ -42,6 +63,58 @@ impl Configuration {
             .add_source(File::with_name(&format!("config/{}", run_mode)).required(false))
             .add_source(Environment::default())
             .build()?;
-        s.try_deserialize()
+        let configuration: Configuration = s.try_deserialize()?;
+        configuration.validate()?;
+        Ok(configuration)
+    }
+
+    // The settings applying to every request are checked once at startup, as invalid ones would fail each of them.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(encoder_defaults) = &self.encoder_defaults {
+            encoder_defaults
+                .validate()
+                .map_err(|e| ConfigError::Message(format!("Invalid `encoder_defaults`. {}", e)))?;
+        }
+        // no job could ever be admitted
+        if self.max_processing_jobs == Some(0) {
+            return Err(ConfigError::Message(String::from(
+                "Invalid `max_processing_jobs`. It must be greater than 0",
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Configuration {
+    // The mandatory settings along with the given ones, for the tests depending on the configuration.
+    pub fn for_tests(settings: serde_json::Value) -> Configuration {
+        let mut configuration = serde_json::json!({"app_port": 8080, "health_port": 8081});
+        if let (Some(configuration), Some(settings)) =
+            (configuration.as_object_mut(), settings.as_object())
+        {
+            configuration.extend(settings.clone());
+        }
+        serde_json::from_value(configuration).expect("Invalid test configuration")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let valid = Configuration::for_tests(serde_json::json!({
+            "encoder_defaults": {"webp": {"effort": 6}, "png": {"palette": true, "dither": 0.5}}
+        }));
+        assert!(valid.validate().is_ok());
+        let invalid = Configuration::for_tests(serde_json::json!({
+            "encoder_defaults": {"webp": {"effort": 7}}
+        }));
+        assert!(matches!(invalid.validate(), Err(ConfigError::Message(_))));
+
+        let no_jobs = Configuration::for_tests(serde_json::json!({"max_processing_jobs": 0}));
+        assert!(matches!(no_jobs.validate(), Err(ConfigError::Message(_))));
     }
 }
@@ -0,0 +1,231 @@
+// (c) Copyright 2019-2026 OLX
+
+use crate::commons::errors::InvalidParameterError;
+use libvips::ops::{ForeignHeifCompression, ForeignSubsample};
+use serde::{Deserialize, Serialize};
+
+// Per-format encoder settings. Every field is optional: the value provided in the request takes precedence, then the
+// one from the `encoder_defaults` configuration and lastly the value Dali has always used.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct EncoderOptions {
+    #[serde(default)]
+    pub jpeg: JpegOptions,
+    #[serde(default)]
+    pub webp: WebpOptions,
+    #[serde(default)]
+    pub png: PngOptions,
+    #[serde(default)]
+    pub heic: HeicOptions,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct JpegOptions {
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+    pub trellis: Option<bool>,
+    pub progressive: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct WebpOptions {
+    pub lossless: Option<bool>,
+    pub near_lossless: Option<bool>,
+    pub effort: Option<u8>,
+    pub smart_subsample: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct PngOptions {
+    pub palette: Option<bool>,
+    pub dither: Option<f64>,
+    pub colours: Option<u16>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct HeicOptions {
+    pub compression: Option<HeicCompression>,
+    pub effort: Option<u8>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum ChromaSubsampling {
+    Auto,
+    On,
+    Off,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum HeicCompression {
+    Hevc,
+    Avc,
+    Av1,
+}
+
+const WEBP_MAX_EFFORT: u8 = 6;
+const HEIC_MAX_EFFORT: u8 = 9;
+
+impl EncoderOptions {
+    // whether the encoders keep the settings Dali has always used
+    pub fn is_default(&self) -> bool {
+        *self == EncoderOptions::default()
+    }
+
+    pub fn with_defaults(self, defaults: Option<&EncoderOptions>) -> EncoderOptions {
+        let Some(defaults) = defaults else {
+            return self;
+        };
+        EncoderOptions {
+            jpeg: JpegOptions {
+                chroma_subsampling: self
+                    .jpeg
+                    .chroma_subsampling
+                    .or(defaults.jpeg.chroma_subsampling),
+                trellis: self.jpeg.trellis.or(defaults.jpeg.trellis),
+                progressive: self.jpeg.progressive.or(defaults.jpeg.progressive),
+            },
+            webp: WebpOptions {
+                lossless: self.webp.lossless.or(defaults.webp.lossless),
+                near_lossless: self.webp.near_lossless.or(defaults.webp.near_lossless),
+                effort: self.webp.effort.or(defaults.webp.effort),
+                smart_subsample: self.webp.smart_subsample.or(defaults.webp.smart_subsample),
+            },
+            png: PngOptions {
+                palette: self.png.palette.or(defaults.png.palette),
+                dither: self.png.dither.or(defaults.png.dither),
+                colours: self.png.colours.or(defaults.png.colours),
+            },
+            heic: HeicOptions {
+                compression: self.heic.compression.or(defaults.heic.compression),
+                effort: self.heic.effort.or(defaults.heic.effort),
+            },
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), InvalidParameterError> {
+        if let Some(effort) = self.webp.effort.filter(|effort| *effort > WEBP_MAX_EFFORT) {
+            return Err(InvalidParameterError::new(
+                "encoder[webp][effort]",
+                &format!("{} is not between 0 and {}", effort, WEBP_MAX_EFFORT),
+            ));
+        }
+        if let Some(effort) = self.heic.effort.filter(|effort| *effort > HEIC_MAX_EFFORT) {
+            return Err(InvalidParameterError::new(
+                "encoder[heic][effort]",
+                &format!("{} is not between 0 and {}", effort, HEIC_MAX_EFFORT),
+            ));
+        }
+        if let Some(dither) = self
+            .png
+            .dither
+            .filter(|dither| !(0.0..=1.0).contains(dither))
+        {
+            return Err(InvalidParameterError::new(
+                "encoder[png][dither]",
+                &format!("{} is not between 0 and 1", dither),
+            ));
+        }
+        if let Some(colours) = self
+            .png
+            .colours
+            .filter(|colours| !(2..=256).contains(colours))
+        {
+            return Err(InvalidParameterError::new(
+                "encoder[png][colours]",
+                &format!("{} is not between 2 and 256", colours),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl PngOptions {
+    // libvips quantises palette images to a bit depth rather than to an exact number of colours, hence the requested
+    // amount is rounded up to the closest supported depth.
+    pub fn bitdepth(&self) -> i32 {
+        match self.colours {
+            Some(colours) if self.palette.unwrap_or(false) => match colours {
+                0..=2 => 1,
+                3..=4 => 2,
+                5..=16 => 4,
+                _ => 8,
+            },
+            _ => 8,
+        }
+    }
+}
+
+impl From<ChromaSubsampling> for ForeignSubsample {
+    fn from(chroma_subsampling: ChromaSubsampling) -> Self {
+        match chroma_subsampling {
+            ChromaSubsampling::Auto => ForeignSubsample::Auto,
+            ChromaSubsampling::On => ForeignSubsample::On,
+            ChromaSubsampling::Off => ForeignSubsample::Off,
+        }
+    }
+}
+
+impl From<HeicCompression> for ForeignHeifCompression {
+    fn from(compression: HeicCompression) -> Self {
+        match compression {
+            HeicCompression::Hevc => ForeignHeifCompression::Hevc,
+            HeicCompression::Avc => ForeignHeifCompression::Avc,
+            HeicCompression::Av1 => ForeignHeifCompression::Av1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_options_take_precedence() {
+        let request = EncoderOptions {
+            webp: WebpOptions {
+                effort: Some(6),
+                ..WebpOptions::default()
+            },
+            ..EncoderOptions::default()
+        };
+        let defaults = EncoderOptions {
+            webp: WebpOptions {
+                effort: Some(4),
+                lossless: Some(true),
+                ..WebpOptions::default()
+            },
+            ..EncoderOptions::default()
+        };
+        let merged = request.with_defaults(Some(&defaults));
+        assert_eq!(merged.webp.effort, Some(6));
+        assert_eq!(merged.webp.lossless, Some(true));
+        assert_eq!(merged.webp.near_lossless, None);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(EncoderOptions::default().validate().is_ok());
+        let mut options = EncoderOptions::default();
+        options.webp.effort = Some(7);
+        assert!(options.validate().is_err());
+        let mut options = EncoderOptions::default();
+        options.heic.effort = Some(9);
+        options.png.dither = Some(1.5);
+        assert!(options.validate().is_err());
+        let mut options = EncoderOptions::default();
+        options.png.colours = Some(1);
+        assert!(options.validate().is_err());
+    }
+
+    #[test]
+    fn test_png_bitdepth() {
+        let mut options = PngOptions::default();
+        assert_eq!(options.bitdepth(), 8);
+        options.colours = Some(16);
+        assert_eq!(options.bitdepth(), 8);
+        options.palette = Some(true);
+        assert_eq!(options.bitdepth(), 4);
+        options.colours = Some(2);
+        assert_eq!(options.bitdepth(), 1);
+        options.colours = Some(200);
+        assert_eq!(options.bitdepth(), 8);
+    }
+}
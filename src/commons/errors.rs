@@ -46,3 +46,27 @@ impl From<InvalidSizeError> for libvips::error::Error {
         libvips::error::Error::InitializationError("Invalid size")
     }
 }
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidParameterError {
+    msg: String,
+}
+
+impl InvalidParameterError {
+    pub fn new(parameter: &str, reason: &str) -> InvalidParameterError {
+        let message = format!("Parameter `{}` is not valid: {}.", parameter, reason);
+        InvalidParameterError { msg: message }
+    }
+}
+
+impl fmt::Display for InvalidParameterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl Error for InvalidParameterError {
+    fn description(&self) -> &str {
+        &self.msg
+    }
+}
@@ -1,15 +1,18 @@
 // (c) Copyright 2019-2026 OLX
 
 pub mod config;
+pub mod encoder;
 pub mod errors;
 #[cfg(feature = "opentelemetry")]
 pub mod open_telemetry;
 
+use encoder::EncoderOptions;
 use errors::InvalidSizeError;
 use libvips::ops::Angle;
 use log::*;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fmt;
+use std::str::FromStr;
 
 pub fn timestamp_millis() -> u128 {
     std::time::SystemTime::now()
@@ -25,12 +28,173 @@ pub struct ProcessImageRequest {
     pub size: Size,
     #[serde(default)]
     pub format: ImageFormat,
-    #[serde(default = "default_quality")]
-    pub quality: i32,
+    #[serde(default)]
+    pub quality: Option<Quality>,
     #[serde(default)]
     pub watermarks: Vec<Watermark>,
     #[serde(default)]
     pub rotation: Option<Rotation>,
+    #[serde(default)]
+    pub page: Option<u16>,
+    #[serde(default)]
+    pub density: Option<u16>,
+    #[serde(default)]
+    pub encoder: EncoderOptions,
+    #[serde(default)]
+    pub max_bytes: Option<u32>,
+    #[serde(default)]
+    pub keep_metadata: Option<KeepMetadata>,
+    #[serde(default)]
+    pub output_profile: OutputProfile,
+    #[serde(default)]
+    pub background: Option<Colour>,
+    #[serde(default)]
+    pub output: OutputMode,
+    #[serde(default)]
+    pub palette_size: Option<u8>,
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+    #[serde(default)]
+    pub trim: Option<Trim>,
+    #[serde(default)]
+    pub renditions: Vec<Rendition>,
+}
+
+// Either a fixed encoder quality or `auto:<target>`, where the target is the minimum structural similarity (0 to 1)
+// the encoded image has to keep compared to the processed one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quality {
+    Fixed(i32),
+    Auto(f64),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum KeepMetadata {
+    None,
+    // only the ICC profile
+    Icc,
+    // only the IPTC and XMP blocks, where the copyright and the attribution are stored
+    Copyright,
+    // EXIF without the GPS tags, ICC and IPTC
+    AllButGps,
+}
+
+// The colour profile the image is converted to. The names match the built-in profiles of libvips.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum OutputProfile {
+    #[default]
+    Srgb,
+    P3,
+}
+
+// Crops the borders of the image which differ from the background by less than the threshold.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Trim {
+    #[serde(default = "default_trim_threshold")]
+    pub threshold: f64,
+    #[serde(default)]
+    pub background: Option<Colour>,
+}
+
+// One of the images rendered from the same source, the missing values being taken from the request.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Rendition {
+    #[serde(default)]
+    pub size: Option<Size>,
+    #[serde(default)]
+    pub format: Option<ImageFormat>,
+    #[serde(default)]
+    pub quality: Option<Quality>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SourceImageRequest {
+    pub image_address: String,
+}
+
+// Several images composed into a single one of the requested size, laid out on a grid.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CollageRequest {
+    pub cells: Vec<CollageCell>,
+    pub layout: Layout,
+    #[serde(default)]
+    pub size: Size,
+    #[serde(default)]
+    pub format: ImageFormat,
+    #[serde(default)]
+    pub quality: Option<Quality>,
+    #[serde(default)]
+    pub encoder: EncoderOptions,
+}
+
+// Two images compared pixel by pixel, once normalised to the same size.
+#[derive(Debug, Deserialize, Clone)]
+pub struct DiffRequest {
+    pub image_address: String,
+    pub other_image_address: String,
+    #[serde(default)]
+    pub output: DiffOutput,
+    // the difference above which a pixel counts as changed, from 0 to 255
+    #[serde(default = "default_change_threshold")]
+    pub threshold: u8,
+}
+
+// Either the metrics as a JSON document, or a heatmap of the differences with the metrics in the headers.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum DiffOutput {
+    #[default]
+    Metrics,
+    Heatmap,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CollageCell {
+    pub image_address: String,
+    #[serde(default = "default_span")]
+    pub row_span: u16,
+    #[serde(default = "default_span")]
+    pub column_span: u16,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Layout {
+    pub rows: u16,
+    pub columns: u16,
+    // the space between the cells, in pixels
+    #[serde(default)]
+    pub gutter: u16,
+    #[serde(default)]
+    pub background: Option<Colour>,
+}
+
+// What the response contains: the processed image, or a JSON document describing it.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum OutputMode {
+    #[default]
+    Image,
+    Blurhash,
+    Thumbhash,
+    Palette,
+    Phash,
+    // the renditions as a JSON document, the images being base64 encoded
+    Manifest,
+}
+
+// The perceptual hash computed with `output=Phash`: the average, the difference or the DCT based hash.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+pub enum HashAlgorithm {
+    Ahash,
+    Dhash,
+    #[default]
+    Phash,
+}
+
+// An RGB colour provided as a hex string, with or without the leading `#`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Colour {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -79,22 +243,137 @@ pub enum VerticalPosition {
     Center,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
 pub enum ImageFormat {
     Png,
     Jpeg,
     Webp,
     Heic,
+    Avif,
+    // resolved to one of the other formats from the `Accept` header before the image gets encoded
+    Auto,
+}
+
+// The format of the downloaded image, detected from its magic bytes. It is only used to decide which loader options
+// are applicable, libvips still performs its own detection when opening the buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Gif,
+    Tiff,
+    Heic,
+    Avif,
+    Pdf,
+    Unknown,
 }
 
-fn default_quality() -> i32 {
-    75
+pub fn default_quality() -> Quality {
+    Quality::Fixed(75)
+}
+
+fn default_trim_threshold() -> f64 {
+    10.0
 }
 
 fn default_watermark_size() -> f64 {
     10.0
 }
 
+fn default_span() -> u16 {
+    1
+}
+
+fn default_change_threshold() -> u8 {
+    16
+}
+
+impl FromStr for Quality {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_prefix("auto:") {
+            Some(target) => match target.parse::<f64>() {
+                Ok(target) if target > 0.0 && target <= 1.0 => Ok(Quality::Auto(target)),
+                _ => Err(format!(
+                    "the auto quality target `{}` is not a number between 0 and 1",
+                    target
+                )),
+            },
+            None => value
+                .parse::<i32>()
+                .map(Quality::Fixed)
+                .map_err(|_| format!("the quality `{}` is not valid", value)),
+        }
+    }
+}
+
+impl Colour {
+    pub const WHITE: Colour = Colour {
+        red: 255,
+        green: 255,
+        blue: 255,
+    };
+
+    pub fn to_vips_background(self) -> Vec<f64> {
+        vec![
+            f64::from(self.red),
+            f64::from(self.green),
+            f64::from(self.blue),
+        ]
+    }
+
+    // The background of an image with the given amount of bands, the alpha one aside. libvips expects one value per
+    // band, greyscale images get the average of the channels.
+    pub fn to_vips_background_for_bands(self, bands: i32) -> Vec<f64> {
+        if bands < 3 {
+            vec![(f64::from(self.red) + f64::from(self.green) + f64::from(self.blue)) / 3.0]
+        } else {
+            self.to_vips_background()
+        }
+    }
+}
+
+impl FromStr for Colour {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let hex = value.strip_prefix('#').unwrap_or(value);
+        let channel = |index: usize| {
+            hex.get(index..index + 2)
+                .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+        };
+        match (hex.len(), channel(0), channel(2), channel(4)) {
+            (6, Some(red), Some(green), Some(blue)) => Ok(Colour { red, green, blue }),
+            _ => Err(format!(
+                "the colour `{}` is not a valid RGB hex value",
+                value
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Colour {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Quality {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 impl Into<Angle> for Rotation {
     fn into(self) -> Angle {
         // we want it inverted as we want it anti-clockwise
@@ -113,6 +392,41 @@ impl fmt::Display for ImageFormat {
             ImageFormat::Png => "png",
             ImageFormat::Webp => "webp",
             ImageFormat::Heic => "heic",
+            ImageFormat::Avif => "avif",
+            ImageFormat::Auto => "auto",
+        };
+        write!(f, "{}", as_str)
+    }
+}
+
+impl fmt::Display for Colour {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
+}
+
+impl fmt::Display for OutputProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let as_str = match self {
+            OutputProfile::Srgb => "srgb",
+            OutputProfile::P3 => "p3",
+        };
+        write!(f, "{}", as_str)
+    }
+}
+
+impl fmt::Display for SourceFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let as_str = match self {
+            SourceFormat::Jpeg => "jpeg",
+            SourceFormat::Png => "png",
+            SourceFormat::Webp => "webp",
+            SourceFormat::Gif => "gif",
+            SourceFormat::Tiff => "tiff",
+            SourceFormat::Heic => "heic",
+            SourceFormat::Avif => "avif",
+            SourceFormat::Pdf => "pdf",
+            SourceFormat::Unknown => "unknown",
         };
         write!(f, "{}", as_str)
     }
@@ -170,6 +484,127 @@ impl Default for ImageFormat {
     }
 }
 
+impl ImageFormat {
+    fn from_media_type(media_type: &str) -> Option<ImageFormat> {
+        match media_type {
+            "image/png" => Some(ImageFormat::Png),
+            "image/jpeg" => Some(ImageFormat::Jpeg),
+            "image/webp" => Some(ImageFormat::Webp),
+            "image/heic" => Some(ImageFormat::Heic),
+            "image/avif" => Some(ImageFormat::Avif),
+            _ => None,
+        }
+    }
+}
+
+impl SourceFormat {
+    pub fn supports_pages(&self) -> bool {
+        matches!(
+            self,
+            SourceFormat::Pdf
+                | SourceFormat::Tiff
+                | SourceFormat::Heic
+                | SourceFormat::Avif
+                | SourceFormat::Gif
+                | SourceFormat::Webp
+        )
+    }
+}
+
+pub fn detect_source_format(buffer: &[u8]) -> SourceFormat {
+    match buffer {
+        [0xFF, 0xD8, 0xFF, ..] => SourceFormat::Jpeg,
+        [0x89, b'P', b'N', b'G', ..] => SourceFormat::Png,
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => SourceFormat::Webp,
+        [b'G', b'I', b'F', b'8', ..] => SourceFormat::Gif,
+        [b'I', b'I', 0x2A, 0x00, ..] | [b'M', b'M', 0x00, 0x2A, ..] => SourceFormat::Tiff,
+        [b'%', b'P', b'D', b'F', ..] => SourceFormat::Pdf,
+        [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..] if brand.len() >= 4 => match &brand[..4] {
+            b"avif" | b"avis" => SourceFormat::Avif,
+            b"heic" | b"heix" | b"hevc" | b"hevx" | b"mif1" | b"msf1" => SourceFormat::Heic,
+            _ => SourceFormat::Unknown,
+        },
+        _ => SourceFormat::Unknown,
+    }
+}
+
+// Returns the formats the client declared it accepts, restricted to the ones enabled for the negotiation and in their
+// order. Wildcards (`image/*` and `*/*`) accept every enabled format but the ones explicitly refused with `q=0`.
+pub fn get_accepted_formats(
+    accept_header: Option<&str>,
+    enabled: &[ImageFormat],
+) -> Vec<ImageFormat> {
+    let mut accepted = Vec::new();
+    let mut refused = Vec::new();
+    let mut wildcard = false;
+    for media_range in accept_header.unwrap_or_default().split(',') {
+        let mut parts = media_range.split(';').map(str::trim);
+        let media_type = parts.next().unwrap_or_default().to_lowercase();
+        let rejected = parts.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+        match media_type.as_str() {
+            "*/*" | "image/*" => wildcard |= !rejected,
+            media_type => {
+                if let Some(format) = ImageFormat::from_media_type(media_type) {
+                    if rejected {
+                        refused.push(format);
+                    } else {
+                        accepted.push(format);
+                    }
+                }
+            }
+        }
+    }
+    enabled
+        .iter()
+        .copied()
+        .filter(|format| !refused.contains(format) && (wildcard || accepted.contains(format)))
+        .collect()
+}
+
+// Picks the best of the accepted formats: AVIF, then WEBP and then PNG for images with transparency or JPEG for the
+// rest. When the client accepts none of them, the fallback is still served.
+pub fn negotiate_format(accepted: &[ImageFormat], has_alpha: bool) -> ImageFormat {
+    let fallback = if has_alpha {
+        ImageFormat::Png
+    } else {
+        ImageFormat::Jpeg
+    };
+    [ImageFormat::Avif, ImageFormat::Webp, fallback]
+        .into_iter()
+        .find(|format| accepted.contains(format))
+        .unwrap_or(fallback)
+}
+
+// Builds the option string passed to `VipsImage::new_from_buffer`. Loaders fail on options they don't know about,
+// hence `page` is only set for the multi-page formats and `dpi` only for PDF.
+pub fn get_loader_options(
+    source_format: SourceFormat,
+    page: Option<u16>,
+    density: Option<u16>,
+    sequential: bool,
+) -> String {
+    let mut options = Vec::new();
+    if sequential {
+        options.push(String::from("access=VIPS_ACCESS_SEQUENTIAL"));
+    }
+    if let Some(page) = page.filter(|_| source_format.supports_pages()) {
+        options.push(format!("page={}", page));
+    }
+    if let Some(density) = density.filter(|_| source_format == SourceFormat::Pdf) {
+        options.push(format!("dpi={}", density));
+    }
+    if options.is_empty() {
+        String::new()
+    } else {
+        format!("[{}]", options.join(","))
+    }
+}
+
 fn get_ratio(desired_measure: i32, original_measure: i32, opposite_orig_measure: i32) -> i32 {
     let ratio = desired_measure as f32 / original_measure as f32;
     (opposite_orig_measure as f32 * ratio) as i32
@@ -718,6 +1153,144 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_detect_source_format() {
+        assert_eq!(
+            detect_source_format(&[0xFF, 0xD8, 0xFF, 0xE0]),
+            SourceFormat::Jpeg
+        );
+        assert_eq!(detect_source_format(b"%PDF-1.7"), SourceFormat::Pdf);
+        assert_eq!(detect_source_format(b"II*\0\x08"), SourceFormat::Tiff);
+        assert_eq!(
+            detect_source_format(b"\0\0\0\x18ftypheic\0\0\0\0"),
+            SourceFormat::Heic
+        );
+        assert_eq!(
+            detect_source_format(b"\0\0\0\x1cftypavif\0\0\0\0"),
+            SourceFormat::Avif
+        );
+        assert_eq!(
+            detect_source_format(b"RIFF\0\0\0\0WEBPVP8 "),
+            SourceFormat::Webp
+        );
+        assert_eq!(detect_source_format(b"\0\0"), SourceFormat::Unknown);
+    }
+
+    #[test]
+    fn test_loader_options() {
+        assert_eq!(
+            get_loader_options(SourceFormat::Jpeg, Some(2), Some(150), true),
+            "[access=VIPS_ACCESS_SEQUENTIAL]"
+        );
+        assert_eq!(
+            get_loader_options(SourceFormat::Jpeg, None, None, false),
+            ""
+        );
+        assert_eq!(
+            get_loader_options(SourceFormat::Pdf, Some(2), Some(150), false),
+            "[page=2,dpi=150]"
+        );
+        assert_eq!(
+            get_loader_options(SourceFormat::Tiff, Some(1), Some(150), true),
+            "[access=VIPS_ACCESS_SEQUENTIAL,page=1]"
+        );
+    }
+
+    #[test]
+    fn test_accepted_formats() {
+        let enabled = [
+            ImageFormat::Avif,
+            ImageFormat::Webp,
+            ImageFormat::Png,
+            ImageFormat::Jpeg,
+        ];
+        assert_eq!(
+            get_accepted_formats(Some("image/avif,image/webp,image/apng,*/*;q=0.8"), &enabled),
+            enabled.to_vec()
+        );
+        assert_eq!(
+            get_accepted_formats(Some("image/webp, image/avif;q=0, image/jpeg"), &enabled),
+            vec![ImageFormat::Webp, ImageFormat::Jpeg]
+        );
+        assert_eq!(
+            get_accepted_formats(Some("image/avif;q=0,*/*"), &enabled),
+            vec![ImageFormat::Webp, ImageFormat::Png, ImageFormat::Jpeg]
+        );
+        assert_eq!(
+            get_accepted_formats(Some("image/*;q=0, image/webp"), &enabled),
+            vec![ImageFormat::Webp]
+        );
+        assert_eq!(
+            get_accepted_formats(Some("image/avif"), &[ImageFormat::Webp]),
+            vec![]
+        );
+        assert_eq!(get_accepted_formats(None, &enabled), vec![]);
+    }
+
+    #[test]
+    fn test_negotiate_format() {
+        assert_eq!(
+            negotiate_format(&[ImageFormat::Webp, ImageFormat::Avif], false),
+            ImageFormat::Avif
+        );
+        assert_eq!(
+            negotiate_format(&[ImageFormat::Webp, ImageFormat::Jpeg], true),
+            ImageFormat::Webp
+        );
+        assert_eq!(
+            negotiate_format(&[ImageFormat::Png, ImageFormat::Jpeg], true),
+            ImageFormat::Png
+        );
+        assert_eq!(
+            negotiate_format(&[ImageFormat::Png, ImageFormat::Jpeg], false),
+            ImageFormat::Jpeg
+        );
+        assert_eq!(negotiate_format(&[], true), ImageFormat::Png);
+    }
+
+    #[test]
+    fn test_parse_quality() {
+        assert_eq!("75".parse(), Ok(Quality::Fixed(75)));
+        assert_eq!("auto:0.95".parse(), Ok(Quality::Auto(0.95)));
+        assert!("auto:1.5".parse::<Quality>().is_err());
+        assert!("auto:".parse::<Quality>().is_err());
+        assert!("high".parse::<Quality>().is_err());
+    }
+
+    #[test]
+    fn test_parse_colour() {
+        assert_eq!(
+            "#1a2B3c".parse(),
+            Ok(Colour {
+                red: 26,
+                green: 43,
+                blue: 60
+            })
+        );
+        assert_eq!("ffffff".parse(), Ok(Colour::WHITE));
+        assert!("#fff".parse::<Colour>().is_err());
+        assert!("#gggggg".parse::<Colour>().is_err());
+        assert!("#ffffff00".parse::<Colour>().is_err());
+        assert_eq!(Colour::WHITE.to_string(), "#ffffff");
+    }
+
+    #[test]
+    fn test_parse_renditions() {
+        let request: ProcessImageRequest = serde_qs::from_str(
+            "image_address=a.jpg&format=Webp&renditions[0][size][width]=300\
+             &renditions[1][size][width]=600&renditions[1][format]=Avif&renditions[1][quality]=60",
+        )
+        .unwrap();
+        assert_eq!(request.renditions.len(), 2);
+        assert_eq!(
+            request.renditions[0].size.as_ref().unwrap().width,
+            Some(300)
+        );
+        assert_eq!(request.renditions[0].format, None);
+        assert_eq!(request.renditions[1].format, Some(ImageFormat::Avif));
+        assert_eq!(request.renditions[1].quality, Some(Quality::Fixed(60)));
+    }
+
     #[test]
     fn test_center_watermark() {
         assert_eq!(
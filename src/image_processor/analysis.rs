@@ -0,0 +1,82 @@
+// (c) Copyright 2019-2026 OLX
+
+// Simple photo quality scores, meant to warn about blurry, badly exposed or blank pictures rather than to rank them.
+
+use serde::Serialize;
+
+// grey levels up to this one are considered underexposed
+const UNDEREXPOSED_MAX_LEVEL: usize = 15;
+// grey levels from this one are considered overexposed
+const OVEREXPOSED_MIN_LEVEL: usize = 240;
+// images whose grey levels deviate less than this from their mean are considered mostly uniform
+const BLANK_MAX_DEVIATION: f64 = 8.0;
+
+#[derive(Serialize)]
+pub struct ImageAnalysis {
+    // the dimensions of the upright image
+    pub width: i32,
+    pub height: i32,
+    pub megapixels: f64,
+    pub aspect_ratio: f64,
+    // the variance of the Laplacian of the grayscale image, the lower the blurrier
+    pub sharpness: f64,
+    pub underexposed_ratio: f64,
+    pub overexposed_ratio: f64,
+    pub is_blank: bool,
+}
+
+impl ImageAnalysis {
+    pub fn new(
+        width: i32,
+        height: i32,
+        sharpness: f64,
+        histogram: &[u32],
+        grey_deviation: f64,
+    ) -> ImageAnalysis {
+        let (underexposed_ratio, overexposed_ratio) = get_exposure_ratios(histogram);
+        ImageAnalysis {
+            width,
+            height,
+            megapixels: f64::from(width) * f64::from(height) / 1_000_000.0,
+            aspect_ratio: f64::from(width) / f64::from(height),
+            sharpness,
+            underexposed_ratio,
+            overexposed_ratio,
+            is_blank: grey_deviation < BLANK_MAX_DEVIATION,
+        }
+    }
+}
+
+// The shares of the pixels which are too dark and too bright, from the 256 bins histogram of the grey levels.
+fn get_exposure_ratios(histogram: &[u32]) -> (f64, f64) {
+    let total: u64 = histogram.iter().map(|count| u64::from(*count)).sum();
+    if total == 0 {
+        return (0.0, 0.0);
+    }
+    let count = |levels: &[u32]| levels.iter().map(|count| u64::from(*count)).sum::<u64>() as f64;
+    (
+        count(&histogram[..=UNDEREXPOSED_MAX_LEVEL]) / total as f64,
+        count(&histogram[OVEREXPOSED_MIN_LEVEL..]) / total as f64,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_analysis() {
+        let mut histogram = [0; 256];
+        histogram[0] = 10;
+        histogram[128] = 60;
+        histogram[255] = 30;
+        let analysis = ImageAnalysis::new(1600, 1200, 250.0, &histogram, 40.0);
+        assert_eq!(analysis.underexposed_ratio, 0.1);
+        assert_eq!(analysis.overexposed_ratio, 0.3);
+        assert_eq!(analysis.megapixels, 1.92);
+        assert!((analysis.aspect_ratio - 4.0 / 3.0).abs() < f64::EPSILON);
+        assert!(!analysis.is_blank);
+        assert!(ImageAnalysis::new(10, 10, 0.0, &histogram, 1.5).is_blank);
+        assert_eq!(get_exposure_ratios(&[0; 256]), (0.0, 0.0));
+    }
+}
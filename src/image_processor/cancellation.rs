@@ -0,0 +1,143 @@
+// (c) Copyright 2019-2026 OLX
+
+// Cooperative cancellation of the processing. The request side flags the work once nobody waits for it anymore, e.g.
+// when the request deadline is exceeded or the client went away, and the processing gives up at its next stage rather
+// than running to completion. The token is made current for the worker thread, so that the processing functions don't
+// have to carry it around. The images libvips is computing are killed along with the token, aborting the encoding or
+// the pipeline in progress instead of waiting for it to complete.
+
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use libvips::VipsImage;
+
+use crate::image_processor::ProcessingError;
+
+thread_local! {
+    static CURRENT_TOKEN: RefCell<Option<CancellationToken>> = const { RefCell::new(None) };
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<TokenState>);
+
+#[derive(Debug, Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    running: Mutex<Vec<RunningImage>>,
+}
+
+// An image borrowed by a `KillOnCancel` guard, which unregisters it before the borrow ends.
+#[derive(Debug, PartialEq)]
+struct RunningImage(*const VipsImage);
+
+// The image is only reached from another thread to raise its kill flag, which the libvips workers poll for this very
+// purpose.
+unsafe impl Send for RunningImage {}
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        for image in self.0.running.lock().unwrap().iter() {
+            // SAFETY: the image stays registered only while its guard borrows it
+            unsafe { (*image.0).image_set_kill(true) };
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Runs the processing with this token as the current one of the thread.
+    pub fn run<T>(&self, process: impl FnOnce() -> T) -> T {
+        let previous = CURRENT_TOKEN.with(|current| current.replace(Some(self.clone())));
+        let result = process();
+        CURRENT_TOKEN.with(|current| current.replace(previous));
+        result
+    }
+
+    // Cancels the token once the returned guard is dropped, along with the future awaiting the processing.
+    pub fn cancel_on_drop(&self) -> CancelOnDrop {
+        CancelOnDrop(self.clone())
+    }
+}
+
+pub struct CancelOnDrop(CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+pub struct KillOnCancel<'a> {
+    token: Option<CancellationToken>,
+    image: PhantomData<&'a VipsImage>,
+    ptr: *const VipsImage,
+}
+
+impl Drop for KillOnCancel<'_> {
+    fn drop(&mut self) {
+        if let Some(token) = &self.token {
+            let ptr = RunningImage(self.ptr);
+            token
+                .0
+                .running
+                .lock()
+                .unwrap()
+                .retain(|image| *image != ptr);
+        }
+    }
+}
+
+// Kills the image once the processing running on this thread gets cancelled, for as long as the returned guard lives.
+// libvips then fails the computation of its pixels, e.g. the encoding of the image or the pipeline leading to it.
+pub fn kill_on_cancel(image: &VipsImage) -> KillOnCancel<'_> {
+    let token = CURRENT_TOKEN.with(|current| current.borrow().clone());
+    if let Some(token) = &token {
+        token.0.running.lock().unwrap().push(RunningImage(image));
+        // cancelled before the image got registered
+        if token.is_cancelled() {
+            image.image_set_kill(true);
+        }
+    }
+    KillOnCancel {
+        token,
+        image: PhantomData,
+        ptr: image,
+    }
+}
+
+// Fails when the processing running on this thread was cancelled. Outside of a cancellable processing it never does.
+pub fn ensure_not_cancelled() -> Result<(), ProcessingError> {
+    let cancelled = CURRENT_TOKEN.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    });
+    if cancelled {
+        Err(ProcessingError::Cancelled)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancellation() {
+        let token = CancellationToken::default();
+        assert!(token.run(ensure_not_cancelled).is_ok());
+        drop(token.cancel_on_drop());
+        assert!(matches!(
+            token.run(ensure_not_cancelled),
+            Err(ProcessingError::Cancelled)
+        ));
+        // the token only applies while its processing runs
+        assert!(ensure_not_cancelled().is_ok());
+    }
+}
@@ -0,0 +1,148 @@
+// (c) Copyright 2019-2026 OLX
+
+// The layout of the collages: a grid whose cells are placed in reading order, each one taking the first free area
+// matching its span, the way CSS grids auto-place their items. The gutter separates the cells, not the borders.
+
+pub const MAX_COLLAGE_DIMENSION: i32 = 4096;
+pub const MAX_LAYOUT_TRACKS: u16 = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Grid {
+    pub width: i32,
+    pub height: i32,
+    pub rows: u16,
+    pub columns: u16,
+    pub gutter: i32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CellArea {
+    pub left: i32,
+    pub top: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Grid {
+    // The area of every cell from their row and column spans, or `None` when they don't fit in the grid or the
+    // gutter leaves no room for them.
+    pub fn place(&self, spans: &[(u16, u16)]) -> Option<Vec<CellArea>> {
+        let rows = usize::from(self.rows);
+        let columns = usize::from(self.columns);
+        let mut taken = vec![false; rows * columns];
+        spans
+            .iter()
+            .map(|&(row_span, column_span)| {
+                let row_span = usize::from(row_span);
+                let column_span = usize::from(column_span);
+                if row_span == 0 || column_span == 0 {
+                    return None;
+                }
+                let (row, column) = (0..rows * columns)
+                    .map(|index| (index / columns, index % columns))
+                    .find(|&(row, column)| {
+                        row + row_span <= rows
+                            && column + column_span <= columns
+                            && (row..row + row_span).all(|r| {
+                                (column..column + column_span).all(|c| !taken[r * columns + c])
+                            })
+                    })?;
+                for r in row..row + row_span {
+                    for c in column..column + column_span {
+                        taken[r * columns + c] = true;
+                    }
+                }
+                let (left, width) =
+                    get_track_span(self.width, columns, self.gutter, column, column_span);
+                let (top, height) = get_track_span(self.height, rows, self.gutter, row, row_span);
+                (width > 0 && height > 0).then_some(CellArea {
+                    left,
+                    top,
+                    width,
+                    height,
+                })
+            })
+            .collect()
+    }
+}
+
+// The offset and the length of `span` tracks starting at `start`, the rounding being spread among the tracks.
+fn get_track_span(
+    length: i32,
+    tracks: usize,
+    gutter: i32,
+    start: usize,
+    span: usize,
+) -> (i32, i32) {
+    let boundary = |index: usize| {
+        let tracks = tracks as i32;
+        (index as i32 * (length + gutter) + tracks / 2) / tracks
+    };
+    let offset = boundary(start);
+    (offset, boundary(start + span) - gutter - offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(left: i32, top: i32, width: i32, height: i32) -> CellArea {
+        CellArea {
+            left,
+            top,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn test_place_grid() {
+        let grid = Grid {
+            width: 600,
+            height: 400,
+            rows: 2,
+            columns: 2,
+            gutter: 10,
+        };
+        assert_eq!(
+            grid.place(&[(1, 1); 4]),
+            Some(vec![
+                area(0, 0, 295, 195),
+                area(305, 0, 295, 195),
+                area(0, 205, 295, 195),
+                area(305, 205, 295, 195),
+            ])
+        );
+        // fewer cells than the grid can hold leave the last ones empty
+        assert_eq!(grid.place(&[(1, 1)]), Some(vec![area(0, 0, 295, 195)]));
+        assert_eq!(grid.place(&[(1, 1); 5]), None);
+        assert_eq!(grid.place(&[(1, 3)]), None);
+        assert_eq!(grid.place(&[(0, 1)]), None);
+        let gutter_only = Grid {
+            gutter: 600,
+            ..grid
+        };
+        assert_eq!(gutter_only.place(&[(1, 1)]), None);
+    }
+
+    #[test]
+    fn test_place_spanning_cells() {
+        // one large image on the left and three smaller ones stacked on the right
+        let grid = Grid {
+            width: 600,
+            height: 300,
+            rows: 3,
+            columns: 2,
+            gutter: 0,
+        };
+        assert_eq!(
+            grid.place(&[(3, 1), (1, 1), (1, 1), (1, 1)]),
+            Some(vec![
+                area(0, 0, 300, 300),
+                area(300, 0, 300, 100),
+                area(300, 100, 300, 100),
+                area(300, 200, 300, 100),
+            ])
+        );
+    }
+}
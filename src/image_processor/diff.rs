@@ -0,0 +1,96 @@
+// (c) Copyright 2019-2026 OLX
+
+// Pixel differences between two RGB images of the same size. A pixel differs by the largest difference among its
+// channels, so that a change of hue counts as much as a change of brightness.
+
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct ImageDiff {
+    // the dimensions the images were compared at
+    pub width: i32,
+    pub height: i32,
+    // from 0 to 255, over every channel of every pixel
+    pub mean_absolute_difference: f64,
+    // in decibels, missing when the images are identical
+    pub psnr: Option<f64>,
+    // the share of the pixels differing by more than the threshold, from 0 to 100
+    pub changed_percentage: f64,
+}
+
+pub fn compare(width: i32, height: i32, first: &[u8], second: &[u8], threshold: u8) -> ImageDiff {
+    let mut absolute_sum = 0_u64;
+    let mut squared_sum = 0_u64;
+    let mut changed = 0_u64;
+    for (first, second) in first.chunks_exact(3).zip(second.chunks_exact(3)) {
+        let differences = get_differences(first, second);
+        absolute_sum += differences.iter().map(|d| u64::from(*d)).sum::<u64>();
+        squared_sum += differences
+            .iter()
+            .map(|d| u64::from(*d).pow(2))
+            .sum::<u64>();
+        if differences.iter().any(|d| *d > threshold) {
+            changed += 1;
+        }
+    }
+    let pixels = (first.len() / 3).max(1) as f64;
+    let mean_squared_error = squared_sum as f64 / (pixels * 3.0);
+    ImageDiff {
+        width,
+        height,
+        mean_absolute_difference: absolute_sum as f64 / (pixels * 3.0),
+        psnr: (squared_sum > 0)
+            .then(|| 10.0 * (f64::from(u8::MAX).powi(2) / mean_squared_error).log10()),
+        changed_percentage: changed as f64 * 100.0 / pixels,
+    }
+}
+
+// The differences as RGB pixels, going from black for identical pixels through red and yellow to white, the "hot"
+// colour map.
+pub fn heatmap(first: &[u8], second: &[u8]) -> Vec<u8> {
+    first
+        .chunks_exact(3)
+        .zip(second.chunks_exact(3))
+        .flat_map(|(first, second)| {
+            let difference = u16::from(*get_differences(first, second).iter().max().unwrap()) * 3;
+            [0, 255, 510].map(|offset| difference.saturating_sub(offset).min(255) as u8)
+        })
+        .collect()
+}
+
+fn get_differences(first: &[u8], second: &[u8]) -> [u8; 3] {
+    [0, 1, 2].map(|channel| first[channel].abs_diff(second[channel]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let first = [10, 20, 30, 100, 100, 100];
+        let identical = compare(2, 1, &first, &first, 16);
+        assert_eq!(identical.mean_absolute_difference, 0.0);
+        assert_eq!(identical.psnr, None);
+        assert_eq!(identical.changed_percentage, 0.0);
+
+        // the second pixel only changes by less than the threshold
+        let second = [10, 20, 90, 100, 110, 100];
+        let diff = compare(2, 1, &first, &second, 16);
+        assert_eq!(diff.mean_absolute_difference, 70.0 / 6.0);
+        assert_eq!(diff.changed_percentage, 50.0);
+        let mean_squared_error: f64 = (3600.0 + 100.0) / 6.0;
+        let psnr = 10.0 * (65025.0 / mean_squared_error).log10();
+        assert!((diff.psnr.unwrap() - psnr).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let first = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let second = [0, 0, 0, 50, 0, 0, 0, 120, 0, 255, 255, 255];
+        assert_eq!(
+            heatmap(&first, &second),
+            [0, 0, 0, 150, 0, 0, 255, 105, 0, 255, 255, 255]
+        );
+    }
+}
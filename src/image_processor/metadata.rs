@@ -0,0 +1,510 @@
+// (c) Copyright 2019-2026 OLX
+
+// The combined `keep_metadata` policies. The encoders only accept a single kind of metadata to keep, so the image is
+// encoded with all of it and the unwanted blocks are removed from the encoded bytes: the segments of JPEG and the
+// chunks of PNG and WebP. The GPS IFD is wiped from the EXIF block in place. The HEIF containers can't be edited that
+// way, they keep the ICC profile alone under these policies.
+
+use std::borrow::Cow;
+
+use libvips::ops::ForeignKeep;
+
+use crate::commons::{ImageFormat, KeepMetadata};
+
+pub const JPEG_SOI: [u8; 2] = [0xff, 0xd8];
+const JPEG_SOS: u8 = 0xda;
+const JPEG_APP1: u8 = 0xe1;
+const JPEG_APP2: u8 = 0xe2;
+pub const JPEG_ICC_SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+const EXIF_SIGNATURE: &[u8] = b"Exif\0\0";
+pub const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+const WEBP_HEADER_LENGTH: usize = 12;
+// flags of the VP8X chunk telling which metadata chunks follow
+const WEBP_ICC_FLAG: u8 = 0x20;
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+const TIFF_GPS_IFD_TAG: u16 = 0x8825;
+const TIFF_ENTRY_LENGTH: usize = 12;
+
+// The metadata removed from the image once encoded with all of it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Removal {
+    pub exif: bool,
+    pub gps: bool,
+    pub xmp: bool,
+    pub icc: bool,
+}
+
+// Which metadata the encoder has to keep and what has to be removed from its output afterwards.
+pub fn get_kept(
+    keep_metadata: Option<KeepMetadata>,
+    keep_icc: bool,
+    format: ImageFormat,
+) -> (ForeignKeep, Removal) {
+    let icc = if keep_icc {
+        ForeignKeep::Icc
+    } else {
+        ForeignKeep::None
+    };
+    let editable = matches!(
+        format,
+        ImageFormat::Jpeg | ImageFormat::Auto | ImageFormat::Png | ImageFormat::Webp
+    );
+    match keep_metadata.unwrap_or(KeepMetadata::None) {
+        KeepMetadata::None => (icc, Removal::default()),
+        KeepMetadata::Icc | KeepMetadata::AllButGps if !editable => {
+            (ForeignKeep::Icc, Removal::default())
+        }
+        KeepMetadata::Copyright if !editable => (icc, Removal::default()),
+        KeepMetadata::Icc => (ForeignKeep::Icc, Removal::default()),
+        KeepMetadata::Copyright => (
+            ForeignKeep::All,
+            Removal {
+                exif: true,
+                icc: !keep_icc,
+                ..Removal::default()
+            },
+        ),
+        // XMP packets can carry the location as well and cannot be edited, hence they're dropped altogether
+        KeepMetadata::AllButGps => (
+            ForeignKeep::All,
+            Removal {
+                gps: true,
+                xmp: true,
+                ..Removal::default()
+            },
+        ),
+    }
+}
+
+// The encoded image without the removed metadata. Returns `None` when the encoded image can't be parsed, rather than
+// letting the metadata through.
+pub fn remove(encoded: Vec<u8>, format: ImageFormat, removal: Removal) -> Option<Vec<u8>> {
+    if removal == Removal::default() {
+        return Some(encoded);
+    }
+    match format {
+        ImageFormat::Jpeg | ImageFormat::Auto => remove_from_jpeg(&encoded, removal),
+        ImageFormat::Png => remove_from_png(&encoded, removal),
+        ImageFormat::Webp => remove_from_webp(&encoded, removal),
+        ImageFormat::Heic | ImageFormat::Avif => None,
+    }
+}
+
+fn remove_from_jpeg(buffer: &[u8], removal: Removal) -> Option<Vec<u8>> {
+    map_jpeg_segments(buffer, |marker, segment| {
+        let payload = &segment[4..];
+        match marker {
+            JPEG_APP1 if payload.starts_with(EXIF_SIGNATURE) => {
+                if removal.exif {
+                    return None;
+                }
+                if !removal.gps {
+                    return Some(Cow::Borrowed(segment));
+                }
+                let mut segment = segment.to_vec();
+                // an EXIF block that can't be parsed is dropped along with its GPS tags
+                remove_gps(&mut segment[4 + EXIF_SIGNATURE.len()..])?;
+                Some(Cow::Owned(segment))
+            }
+            // the other APP1 segments are the XMP packets
+            JPEG_APP1 if removal.xmp => None,
+            JPEG_APP2 if removal.icc && payload.starts_with(JPEG_ICC_SIGNATURE) => None,
+            _ => Some(Cow::Borrowed(segment)),
+        }
+    })
+}
+
+fn remove_from_png(buffer: &[u8], removal: Removal) -> Option<Vec<u8>> {
+    map_png_chunks(buffer, |chunk_type, chunk| {
+        let data = &chunk[8..chunk.len() - 4];
+        match chunk_type {
+            b"eXIf" if removal.exif => None,
+            b"eXIf" if removal.gps => {
+                let mut chunk = chunk.to_vec();
+                let data_end = chunk.len() - 4;
+                let tiff_start = 8 + get_tiff_start(data);
+                remove_gps(&mut chunk[tiff_start..data_end])?;
+                let crc = crc32fast::hash(&chunk[4..data_end]);
+                chunk[data_end..].copy_from_slice(&crc.to_be_bytes());
+                Some(Cow::Owned(chunk))
+            }
+            b"iTXt" | b"tEXt" | b"zTXt" if removal.xmp && data.starts_with(PNG_XMP_KEYWORD) => None,
+            b"iCCP" if removal.icc => None,
+            _ => Some(Cow::Borrowed(chunk)),
+        }
+    })
+}
+
+// The metadata chunks are dropped along with their flag in the VP8X chunk, and the size of the RIFF container is
+// updated accordingly.
+fn remove_from_webp(buffer: &[u8], removal: Removal) -> Option<Vec<u8>> {
+    if buffer.get(0..4)? != b"RIFF" || buffer.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut stripped = Vec::with_capacity(buffer.len());
+    stripped.extend_from_slice(&buffer[..WEBP_HEADER_LENGTH]);
+    let mut removed_flags = 0;
+    let mut position = WEBP_HEADER_LENGTH;
+    while position < buffer.len() {
+        let length = u32::from_le_bytes(buffer.get(position + 4..position + 8)?.try_into().ok()?);
+        // chunks are padded to an even length
+        let padded_length = length as usize + (length as usize & 1);
+        let chunk = buffer.get(position..position + 8 + padded_length)?;
+        match &chunk[..4] {
+            b"EXIF" if removal.exif => removed_flags |= WEBP_EXIF_FLAG,
+            b"EXIF" if removal.gps => {
+                let mut chunk = chunk.to_vec();
+                let data_end = 8 + length as usize;
+                let tiff_start = 8 + get_tiff_start(&chunk[8..data_end]);
+                if remove_gps(&mut chunk[tiff_start..data_end]).is_some() {
+                    stripped.extend_from_slice(&chunk);
+                } else {
+                    removed_flags |= WEBP_EXIF_FLAG;
+                }
+            }
+            b"XMP " if removal.xmp => removed_flags |= WEBP_XMP_FLAG,
+            b"ICCP" if removal.icc => removed_flags |= WEBP_ICC_FLAG,
+            _ => stripped.extend_from_slice(chunk),
+        }
+        position += chunk.len();
+    }
+    if stripped.get(WEBP_HEADER_LENGTH..WEBP_HEADER_LENGTH + 4) == Some(b"VP8X") {
+        stripped[WEBP_HEADER_LENGTH + 8] &= !removed_flags;
+    }
+    let riff_length = u32::try_from(stripped.len() - 8).ok()?;
+    stripped[4..8].copy_from_slice(&riff_length.to_le_bytes());
+    Some(stripped)
+}
+
+// The EXIF blocks of PNG and WebP may be prefixed with the signature of the JPEG segment.
+fn get_tiff_start(exif: &[u8]) -> usize {
+    if exif.starts_with(EXIF_SIGNATURE) {
+        EXIF_SIGNATURE.len()
+    } else {
+        0
+    }
+}
+
+// Wipes the GPS IFD of the TIFF structure of an EXIF block and removes the entry pointing to it from the first IFD.
+// The block keeps its length, so that the offsets of the rest of it remain valid.
+fn remove_gps(tiff: &mut [u8]) -> Option<()> {
+    let big_endian = match tiff.get(0..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let read_u16 = |tiff: &[u8], offset: usize| -> Option<usize> {
+        let bytes = tiff.get(offset..offset + 2)?.try_into().ok()?;
+        Some(usize::from(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }))
+    };
+    let read_u32 = |tiff: &[u8], offset: usize| -> Option<usize> {
+        let bytes = tiff.get(offset..offset + 4)?.try_into().ok()?;
+        usize::try_from(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+        .ok()
+    };
+
+    let first_ifd = read_u32(tiff, 4)?;
+    let entry_count = read_u16(tiff, first_ifd)?;
+    let entries_start = first_ifd + 2;
+    let entries_end = entries_start + entry_count * TIFF_ENTRY_LENGTH;
+    // the entries are followed by the offset of the next IFD
+    tiff.get(entries_start..entries_end + 4)?;
+    let Some(gps_entry) = (0..entry_count)
+        .map(|index| entries_start + index * TIFF_ENTRY_LENGTH)
+        .find(|entry| read_u16(tiff, *entry) == Some(usize::from(TIFF_GPS_IFD_TAG)))
+    else {
+        return Some(());
+    };
+
+    let gps_ifd = read_u32(tiff, gps_entry + 8)?;
+    let gps_entry_count = read_u16(tiff, gps_ifd)?;
+    let gps_ifd_end = gps_ifd + 2 + gps_entry_count * TIFF_ENTRY_LENGTH + 4;
+    tiff.get(gps_ifd..gps_ifd_end)?;
+    for index in 0..gps_entry_count {
+        let entry = gps_ifd + 2 + index * TIFF_ENTRY_LENGTH;
+        let value_length = get_type_length(read_u16(tiff, entry + 2)?) * read_u32(tiff, entry + 4)?;
+        // values of more than 4 bytes are stored apart, the other ones within the entry
+        if value_length > 4 {
+            let value = read_u32(tiff, entry + 8)?;
+            tiff.get_mut(value..value + value_length)?.fill(0);
+        }
+    }
+    tiff[gps_ifd..gps_ifd_end].fill(0);
+
+    tiff.copy_within(gps_entry + TIFF_ENTRY_LENGTH..entries_end + 4, gps_entry);
+    tiff[entries_end - TIFF_ENTRY_LENGTH + 4..entries_end + 4].fill(0);
+    let entry_count = u16::try_from(entry_count - 1).ok()?;
+    let entry_count = if big_endian {
+        entry_count.to_be_bytes()
+    } else {
+        entry_count.to_le_bytes()
+    };
+    tiff[first_ifd..first_ifd + 2].copy_from_slice(&entry_count);
+    Some(())
+}
+
+// The length in bytes of the values of the TIFF field types, those unknown being skipped.
+fn get_type_length(field_type: usize) -> usize {
+    match field_type {
+        // byte, ASCII, signed byte and undefined
+        1 | 2 | 6 | 7 => 1,
+        // short and signed short
+        3 | 8 => 2,
+        // long, signed long and float
+        4 | 9 | 11 => 4,
+        // rationals and double
+        5 | 10 | 12 => 8,
+        _ => 0,
+    }
+}
+
+// Rebuilds the JPEG from the segments returned for each of the segments preceding the scan, the ones for which `None`
+// is returned being dropped. Everything from the start of the scan is copied as is.
+pub fn map_jpeg_segments<'a>(
+    buffer: &'a [u8],
+    mut map: impl FnMut(u8, &'a [u8]) -> Option<Cow<'a, [u8]>>,
+) -> Option<Vec<u8>> {
+    if !buffer.starts_with(&JPEG_SOI) {
+        return None;
+    }
+    let mut mapped = Vec::with_capacity(buffer.len());
+    mapped.extend_from_slice(&JPEG_SOI);
+    let mut position = JPEG_SOI.len();
+    loop {
+        if *buffer.get(position)? != 0xff {
+            return None;
+        }
+        let marker = *buffer.get(position + 1)?;
+        if marker == 0xff {
+            // fill byte
+            position += 1;
+            continue;
+        }
+        if marker == JPEG_SOS {
+            mapped.extend_from_slice(&buffer[position..]);
+            return Some(mapped);
+        }
+        if marker == 0x01 || (0xd0..=0xd7).contains(&marker) {
+            // standalone markers, without any payload
+            mapped.extend_from_slice(&buffer[position..position + 2]);
+            position += 2;
+            continue;
+        }
+        let length = usize::from(u16::from_be_bytes([
+            *buffer.get(position + 2)?,
+            *buffer.get(position + 3)?,
+        ]));
+        let segment = buffer.get(position..position + 2 + length)?;
+        if segment.len() < 4 {
+            return None;
+        }
+        if let Some(segment) = map(marker, segment) {
+            mapped.extend_from_slice(&segment);
+        }
+        position += 2 + length;
+    }
+}
+
+// Rebuilds the PNG from the chunks returned for each of its chunks, their length, type, data and CRC included. The
+// ones for which `None` is returned are dropped.
+pub fn map_png_chunks<'a>(
+    buffer: &'a [u8],
+    mut map: impl FnMut(&[u8], &'a [u8]) -> Option<Cow<'a, [u8]>>,
+) -> Option<Vec<u8>> {
+    if !buffer.starts_with(&PNG_SIGNATURE) {
+        return None;
+    }
+    let mut mapped = Vec::with_capacity(buffer.len());
+    mapped.extend_from_slice(&PNG_SIGNATURE);
+    let mut position = PNG_SIGNATURE.len();
+    loop {
+        let length = u32::from_be_bytes(buffer.get(position..position + 4)?.try_into().ok()?);
+        let chunk = buffer.get(position..position + 12 + length as usize)?;
+        let chunk_type = &chunk[4..8];
+        if let Some(chunk) = map(chunk_type, chunk) {
+            mapped.extend_from_slice(&chunk);
+        }
+        position += chunk.len();
+        if chunk_type == b"IEND" {
+            return Some(mapped);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a big-endian EXIF block with a Make tag, the GPS IFD holding a latitude, and a copyright
+    fn get_tiff() -> Vec<u8> {
+        let mut tiff = b"MM\0\x2a\0\0\0\x08".to_vec();
+        // first IFD of 3 entries, at 8
+        tiff.extend_from_slice(&[0, 3]);
+        tiff.extend_from_slice(&[0x01, 0x0f, 0, 2, 0, 0, 0, 4, b'D', b'a', b'l', 0]);
+        tiff.extend_from_slice(&[0x82, 0x98, 0, 2, 0, 0, 0, 4, b'O', b'L', b'X', 0]);
+        tiff.extend_from_slice(&[0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 50]);
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        // GPS IFD of 2 entries at 50, the latitude being stored at 80
+        tiff.extend_from_slice(&[0, 2]);
+        tiff.extend_from_slice(&[0, 1, 0, 2, 0, 0, 0, 2, b'N', 0, 0, 0]);
+        tiff.extend_from_slice(&[0, 2, 0, 5, 0, 0, 0, 3, 0, 0, 0, 80]);
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        tiff.extend_from_slice(&[
+            0, 0, 0, 48, 0, 0, 0, 1, 0, 0, 0, 8, 0, 0, 0, 1, 0, 0, 0, 30, 0, 0, 0, 1,
+        ]);
+        tiff
+    }
+
+    #[test]
+    fn test_remove_gps() {
+        let mut tiff = get_tiff();
+        let length = tiff.len();
+        assert_eq!(remove_gps(&mut tiff), Some(()));
+        assert_eq!(tiff.len(), length);
+        assert_eq!(&tiff[8..10], &[0, 2]);
+        // the copyright follows the make, then the offset of the next IFD
+        assert_eq!(&tiff[22..26], &[0x82, 0x98, 0, 2]);
+        assert!(tiff[34..].iter().all(|byte| *byte == 0));
+
+        // nothing to remove
+        let mut without_gps = tiff.clone();
+        assert_eq!(remove_gps(&mut without_gps), Some(()));
+        assert_eq!(without_gps, tiff);
+        assert_eq!(remove_gps(&mut get_tiff()[..40]), None);
+        assert_eq!(remove_gps(&mut b"GIF89a".to_vec()), None);
+    }
+
+    #[test]
+    fn test_remove_from_jpeg() {
+        let segment = |marker: u8, payload: &[u8]| {
+            let length = (payload.len() + 2) as u16;
+            [&[0xff, marker], &length.to_be_bytes()[..], payload].concat()
+        };
+        let exif = segment(JPEG_APP1, &[EXIF_SIGNATURE, &get_tiff()].concat());
+        let xmp = segment(JPEG_APP1, b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>");
+        let icc = segment(JPEG_APP2, b"ICC_PROFILE\0\x01\x01profile");
+        let iptc = segment(0xed, b"Photoshop 3.0\0iptc");
+        let scan = [
+            &segment(JPEG_SOS, b"\0scan")[..],
+            b"\xe1entropy coded\xff\xd9",
+        ]
+        .concat();
+        let source = [&JPEG_SOI[..], &exif, &xmp, &icc, &iptc, &scan].concat();
+
+        let (keep, removal) = get_kept(Some(KeepMetadata::Copyright), false, ImageFormat::Jpeg);
+        assert!(matches!(keep, ForeignKeep::All));
+        let expected = [&JPEG_SOI[..], &xmp, &iptc, &scan].concat();
+        assert_eq!(
+            remove(source.clone(), ImageFormat::Jpeg, removal),
+            Some(expected)
+        );
+
+        let (_, removal) = get_kept(Some(KeepMetadata::AllButGps), false, ImageFormat::Jpeg);
+        let removed = remove(source.clone(), ImageFormat::Jpeg, removal).unwrap();
+        assert_eq!(removed.len(), source.len() - xmp.len());
+        let mut tiff = get_tiff();
+        remove_gps(&mut tiff).unwrap();
+        let expected_exif = segment(JPEG_APP1, &[EXIF_SIGNATURE, &tiff].concat());
+        assert_eq!(
+            removed,
+            [&JPEG_SOI[..], &expected_exif, &icc, &iptc, &scan].concat()
+        );
+    }
+
+    #[test]
+    fn test_remove_from_png() {
+        let chunk = |chunk_type: &[u8; 4], data: &[u8]| {
+            let length = data.len() as u32;
+            let crc = crc32fast::hash(&[&chunk_type[..], data].concat());
+            [
+                &length.to_be_bytes()[..],
+                chunk_type,
+                data,
+                &crc.to_be_bytes(),
+            ]
+            .concat()
+        };
+        let header = chunk(b"IHDR", &[0; 13]);
+        let icc = chunk(b"iCCP", b"sRGB\0\0profile");
+        let xmp = chunk(b"iTXt", b"XML:com.adobe.xmp\0\0\0\0\0<x:xmpmeta/>");
+        let exif = chunk(b"eXIf", &get_tiff());
+        let data = chunk(b"IDAT", b"pixels");
+        let end = chunk(b"IEND", b"");
+        let source = [&PNG_SIGNATURE[..], &header, &icc, &xmp, &exif, &data, &end].concat();
+
+        let (_, removal) = get_kept(Some(KeepMetadata::AllButGps), true, ImageFormat::Png);
+        let mut tiff = get_tiff();
+        remove_gps(&mut tiff).unwrap();
+        let expected = [
+            &PNG_SIGNATURE[..],
+            &header,
+            &icc,
+            &chunk(b"eXIf", &tiff),
+            &data,
+            &end,
+        ]
+        .concat();
+        assert_eq!(
+            remove(source.clone(), ImageFormat::Png, removal),
+            Some(expected)
+        );
+        assert_eq!(
+            remove(source[..20].to_vec(), ImageFormat::Png, removal),
+            None
+        );
+    }
+
+    #[test]
+    fn test_remove_from_webp() {
+        let chunk = |fourcc: &[u8; 4], data: &[u8]| {
+            let length = data.len() as u32;
+            let padding: &[u8] = if data.len() % 2 == 1 { &[0] } else { &[] };
+            [&fourcc[..], &length.to_le_bytes(), data, padding].concat()
+        };
+        let webp = |chunks: &[&[u8]]| {
+            let chunks = chunks.concat();
+            let length = (chunks.len() + 4) as u32;
+            [&b"RIFF"[..], &length.to_le_bytes(), b"WEBP", &chunks].concat()
+        };
+        let vp8x = |flags: u8| chunk(b"VP8X", &[flags, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let icc = chunk(b"ICCP", b"profile");
+        let image = chunk(b"VP8 ", b"frame");
+        let exif = chunk(b"EXIF", &[EXIF_SIGNATURE, &get_tiff()].concat());
+        let xmp = chunk(b"XMP ", b"<x:xmpmeta/>");
+        let flags = WEBP_ICC_FLAG | WEBP_EXIF_FLAG | WEBP_XMP_FLAG;
+        let source = webp(&[&vp8x(flags), &icc, &image, &exif, &xmp]);
+
+        let (_, removal) = get_kept(Some(KeepMetadata::Copyright), false, ImageFormat::Webp);
+        let expected = webp(&[&vp8x(WEBP_XMP_FLAG), &image, &xmp]);
+        assert_eq!(
+            remove(source.clone(), ImageFormat::Webp, removal),
+            Some(expected)
+        );
+
+        let (_, removal) = get_kept(Some(KeepMetadata::AllButGps), false, ImageFormat::Webp);
+        let mut tiff = get_tiff();
+        remove_gps(&mut tiff).unwrap();
+        let exif = chunk(b"EXIF", &[EXIF_SIGNATURE, &tiff].concat());
+        let expected = webp(&[&vp8x(WEBP_ICC_FLAG | WEBP_EXIF_FLAG), &icc, &image, &exif]);
+        assert_eq!(remove(source, ImageFormat::Webp, removal), Some(expected));
+    }
+
+    #[test]
+    fn test_get_kept_for_heif() {
+        let (keep, removal) = get_kept(Some(KeepMetadata::AllButGps), false, ImageFormat::Avif);
+        assert!(matches!(keep, ForeignKeep::Icc));
+        assert_eq!(removal, Removal::default());
+        let (keep, _) = get_kept(Some(KeepMetadata::Copyright), false, ImageFormat::Heic);
+        assert!(matches!(keep, ForeignKeep::None));
+    }
+}
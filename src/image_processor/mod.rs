@@ -1,53 +1,368 @@
 // (c) Copyright 2019-2026 OLX
 
+use crate::commons::config::Configuration;
+use crate::commons::encoder::EncoderOptions;
+use crate::commons::errors::InvalidParameterError;
 use crate::commons::*;
 use libvips::ops;
 use libvips::Result;
 use libvips::VipsImage;
 use log::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::sync::Arc;
+use thiserror::Error;
+
+pub mod analysis;
+pub mod cancellation;
+pub mod collage;
+pub mod diff;
+pub mod metadata;
+pub mod palette;
+pub mod passthrough;
+pub mod perceptual_hash;
+pub mod placeholder;
+
+const DEFAULT_MAX_BYTES_MIN_QUALITY: i32 = 30;
+const MAX_BYTES_DOWNSCALE_STEP: f64 = 0.8;
+const AUTO_QUALITY_CANDIDATES: [i32; 6] = [45, 55, 65, 75, 85, 95];
+// the SSIM constants for 8-bit images as defined in the original paper: (0.01 * 255)^2 and (0.03 * 255)^2
+const SSIM_C1: f64 = 6.5025;
+const SSIM_C2: f64 = 58.5225;
+const SSIM_SIGMA: f64 = 1.5;
+// horizontal and vertical BlurHash components, the ones recommended for most images
+const BLURHASH_COMPONENTS: (usize, usize) = (4, 3);
+const PALETTE_MAX_DIMENSION: i32 = 100;
+// the quality scores are computed at a fixed scale so that they are comparable across resolutions
+const ANALYSIS_MAX_DIMENSION: i32 = 1024;
+const DIFF_MAX_DIMENSION: i32 = 1024;
+const LAPLACIAN_KERNEL: [f64; 9] = [0.0, 1.0, 0.0, 1.0, -4.0, 1.0, 0.0, 1.0, 0.0];
+// the EXIF tags reported by the image info, the location is deliberately left out. They're matched by their number as
+// rexif doesn't know all of them, the artist being parsed as `UnknownToMe`.
+const INFO_EXIF_TAGS: [(u16, &str); 10] = [
+    (0x010f, "make"),
+    (0x0110, "model"),
+    (0x0131, "software"),
+    (0x9003, "date_time_original"),
+    (0x013b, "artist"),
+    (0x8298, "copyright"),
+    (0x829a, "exposure_time"),
+    (0x829d, "f_number"),
+    (0x8827, "iso_speed_ratings"),
+    (0x920a, "focal_length"),
+];
+
+// The failures of the processing, mapped to the responses by the routes.
+#[derive(Error, Debug)]
+pub enum ProcessingError {
+    #[error("the image processing with libvips has failed")]
+    LibvipsFailed(libvips::error::Error),
+    #[error("{0}")]
+    InvalidParameter(#[from] InvalidParameterError),
+    #[error("the image cannot be encoded within `{0}` bytes")]
+    TargetSizeUnreachable(u32),
+    #[error("the image of {1}x{2} pixels exceeds the `{0}` limit")]
+    PixelLimitExceeded(&'static str, i32, i32),
+    #[error("the image processing was cancelled")]
+    Cancelled,
+    #[error("the metadata cannot be removed from the `{0}` image")]
+    MetadataRemovalFailed(ImageFormat),
+}
+
+impl From<libvips::error::Error> for ProcessingError {
+    fn from(error: libvips::error::Error) -> Self {
+        ProcessingError::LibvipsFailed(error)
+    }
+}
+
+struct Encoding<'a> {
+    format: ImageFormat,
+    options: &'a EncoderOptions,
+    keep: ops::ForeignKeep,
+    // removed from the encoded image, which the encoder saved with the metadata to keep
+    removal: metadata::Removal,
+}
+
+impl Encoding<'_> {
+    // Whether the quality has an effect on the encoded image, which lossless PNG ignores as only the palette
+    // quantisation depends on it.
+    fn depends_on_quality(&self) -> bool {
+        self.format != ImageFormat::Png || self.options.png.palette.unwrap_or(false)
+    }
+}
+
+pub struct ProcessedImage {
+    pub bytes: Vec<u8>,
+    pub format: ImageFormat,
+    pub width: i32,
+    pub height: i32,
+    // only set when the quality was determined by Dali rather than taken from the request
+    pub quality: Option<i32>,
+    pub trim_box: Option<TrimBox>,
+    // whether the source bytes are served rather than an encoded image
+    pub passthrough: bool,
+}
+
+// The area kept from the upright source image once its borders are trimmed.
+#[derive(Clone, Copy)]
+pub struct TrimBox {
+    pub left: i32,
+    pub top: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+pub struct Placeholder {
+    pub hash: String,
+    // the dimensions of the upright image, allowing the clients to reserve the right space
+    pub width: i32,
+    pub height: i32,
+}
+
+// What can be told about the source image from its header, without decoding the pixels.
+#[derive(Serialize)]
+pub struct ImageInfo {
+    pub format: String,
+    // the dimensions of the first page, as stored and thus before the EXIF orientation is applied
+    pub width: i32,
+    pub height: i32,
+    pub has_alpha: bool,
+    pub orientation: Option<i64>,
+    pub colour_space: String,
+    pub has_icc_profile: bool,
+    pub pages: i32,
+    pub exif: BTreeMap<&'static str, String>,
+}
 
 pub fn process_image(
     buffer: Vec<u8>,
     wm_buffers: Vec<Arc<Vec<u8>>>,
-    parameters: ProcessImageRequest,
-) -> Result<Vec<u8>> {
+    mut parameters: ProcessImageRequest,
+    accepted_formats: Vec<ImageFormat>,
+    config: &Configuration,
+) -> std::result::Result<ProcessedImage, ProcessingError> {
+    // the source is only served as is when the request itself leaves the encoders alone, the defaults of the
+    // deployment applying to the images Dali encodes
+    let default_encoder = parameters.encoder.is_default();
+    parameters.encoder = parameters
+        .encoder
+        .with_defaults(config.encoder_defaults.as_ref());
     let ProcessImageRequest {
-        image_address: _addr,
         size,
         format,
         quality,
-        watermarks,
         rotation,
-    } = parameters;
+        page,
+        density,
+        max_bytes,
+        keep_metadata,
+        output_profile,
+        trim,
+        background,
+        ..
+    } = &parameters;
+    let orientation = get_orientation(&buffer[..]);
     let needs_rotation = rotation.is_some()
-        || match rexif::parse_buffer_quiet(&buffer[..]).0 {
-            Ok(data) => data.entries.into_iter().any(|e| {
-                e.tag == rexif::ExifTag::Orientation
-                    && e.value.to_i64(0).is_some()
-                    && e.value.to_i64(0).unwrap() != 0
-                    && e.value.to_i64(0).unwrap() != 1
-            }),
-            Err(_) => false,
+        || orientation.is_some_and(|orientation| orientation != 0 && orientation != 1);
+    let source_format = detect_source_format(&buffer[..]);
+    // finding the borders reads the whole image before it gets cropped, which sequential access doesn't allow
+    let options = get_loader_options(
+        source_format,
+        *page,
+        *density,
+        !needs_rotation && trim.is_none(),
+    );
+    let source = VipsImage::new_from_buffer(&buffer[..], &options)?;
+    ensure_input_within_limits(&source, config)?;
+    cancellation::ensure_not_cancelled()?;
+
+    // when the processing would only re-encode the image, the source itself can be served instead
+    let passthrough_enabled = config.passthrough_enabled.unwrap_or(false);
+    let never_larger_than_source = config.never_larger_than_source.unwrap_or(false);
+    let untransformed = (passthrough_enabled || never_larger_than_source)
+        && size.width.is_none()
+        && size.height.is_none()
+        && !needs_rotation
+        && wm_buffers.is_empty()
+        && trim.is_none()
+        && max_bytes.is_none()
+        && page.unwrap_or(0) == 0
+        && *output_profile == OutputProfile::Srgb
+        && default_encoder
+        && background.is_none()
+        && !matches!(quality, Some(Quality::Auto(_)))
+        && !matches!(source.get_interpretation(), Ok(ops::Interpretation::Cmyk));
+    let original = if untransformed {
+        let format = match format {
+            ImageFormat::Auto => negotiate_format(&accepted_formats, source.image_hasalpha()),
+            format => *format,
         };
-    let options = if !needs_rotation {
-        "[access=VIPS_ACCESS_SEQUENTIAL]"
+        passthrough::get_original(&buffer[..], source_format, format, *keep_metadata)
+            .map(|original| (original, format))
     } else {
-        ""
+        None
     };
-    let source = VipsImage::new_from_buffer(&buffer[..], options)?;
+    if passthrough_enabled && quality.is_none() {
+        if let Some((bytes, format)) = original {
+            ensure_output_within_limits(&source, config)?;
+            debug!("Serving the source image as is");
+            return Ok(ProcessedImage {
+                bytes,
+                format,
+                width: source.get_width(),
+                height: source.get_height(),
+                quality: None,
+                trim_box: None,
+                passthrough: true,
+            });
+        }
+    }
 
-    let mut final_image = if needs_rotation {
-        let exif_rotated = ops::autorot(&source)?;
-        debug!("Rotating image to {:?}", rotation);
-        if let Some(rotation) = rotation {
-            let resized = resize_image(exif_rotated, &size)?;
-            ops::rot(&resized, rotation.into())?
+    // downscaling while decoding gives the same dimensions as the full resolution path, the trim aside as it needs
+    // the whole image
+    let shrink_on_load = config.shrink_on_load_enabled.unwrap_or(false)
+        && (size.width.is_some() || size.height.is_some())
+        && trim.is_none()
+        && *output_profile == OutputProfile::Srgb
+        && matches!(
+            source_format,
+            SourceFormat::Jpeg | SourceFormat::Webp | SourceFormat::Heic
+        )
+        && !matches!(source.get_interpretation(), Ok(ops::Interpretation::Cmyk));
+    let (resized, trim_box) = if shrink_on_load {
+        let loader_options = get_loader_options(source_format, *page, *density, false);
+        let thumbnail = load_thumbnail(&buffer[..], &source, orientation, size, loader_options)?;
+        (thumbnail, None)
+    } else {
+        let source = convert_to_output_profile(source, *output_profile, config)?;
+
+        let upright = if needs_rotation {
+            ops::autorot(&source)?
         } else {
-            resize_image(exif_rotated, &size)?
-        }
+            source
+        };
+        // trimmed once upright, so that the box matches the image as displayed and the watermarks are placed on the
+        // trimmed image
+        let (upright, trim_box) = match trim {
+            Some(trim) => trim_borders(upright, trim)?,
+            None => (upright, None),
+        };
+        (resize_image(upright, size)?, trim_box)
+    };
+
+    let processed_image = render(
+        resized,
+        &wm_buffers,
+        &parameters,
+        *format,
+        *quality,
+        &accepted_formats,
+        config,
+    )?;
+    if let Some((original, _)) = original.filter(|(original, original_format)| {
+        never_larger_than_source
+            && *original_format == processed_image.format
+            && original.len() <= processed_image.bytes.len()
+    }) {
+        debug!(
+            "Serving the source image as is, the encoded one is {} bytes larger",
+            processed_image.bytes.len() - original.len()
+        );
+        return Ok(ProcessedImage {
+            bytes: original,
+            quality: None,
+            passthrough: true,
+            ..processed_image
+        });
+    }
+    Ok(ProcessedImage {
+        trim_box,
+        ..processed_image
+    })
+}
+
+// Renders every rendition from a single decoding of the source: the upright and trimmed source is kept in memory,
+// each rendition only paying for its resizing and encoding. The renditions fall back to the size, format and quality
+// of the request.
+pub fn process_renditions(
+    buffer: Vec<u8>,
+    wm_buffers: Vec<Arc<Vec<u8>>>,
+    mut parameters: ProcessImageRequest,
+    accepted_formats: Vec<ImageFormat>,
+    config: &Configuration,
+) -> std::result::Result<Vec<ProcessedImage>, ProcessingError> {
+    parameters.encoder = parameters
+        .encoder
+        .with_defaults(config.encoder_defaults.as_ref());
+    let options = get_loader_options(
+        detect_source_format(&buffer[..]),
+        parameters.page,
+        parameters.density,
+        false,
+    );
+    let source = VipsImage::new_from_buffer(&buffer[..], &options)?;
+    ensure_input_within_limits(&source, config)?;
+    let source = convert_to_output_profile(source, parameters.output_profile, config)?;
+    let upright = ops::autorot(&source)?;
+    let (upright, trim_box) = match &parameters.trim {
+        Some(trim) => trim_borders(upright, trim)?,
+        None => (upright, None),
+    };
+    let upright = VipsImage::image_copy_memory(upright)?;
+
+    parameters
+        .renditions
+        .iter()
+        .map(|rendition| {
+            let size = rendition.size.as_ref().unwrap_or(&parameters.size);
+            debug!("Rendering rendition: {:?}", rendition);
+            let resized = resize_image(ops::copy(&upright)?, size)?;
+            let processed_image = render(
+                resized,
+                &wm_buffers,
+                &parameters,
+                rendition.format.unwrap_or(parameters.format),
+                rendition.quality.or(parameters.quality),
+                &accepted_formats,
+                config,
+            )?;
+            Ok(ProcessedImage {
+                trim_box,
+                ..processed_image
+            })
+        })
+        .collect()
+}
+
+// Rotates, watermarks and encodes the resized image. The format and the quality are taken as arguments rather than
+// from the request as they differ between renditions.
+fn render(
+    resized: VipsImage,
+    wm_buffers: &[Arc<Vec<u8>>],
+    parameters: &ProcessImageRequest,
+    format: ImageFormat,
+    quality: Option<Quality>,
+    accepted_formats: &[ImageFormat],
+    config: &Configuration,
+) -> std::result::Result<ProcessedImage, ProcessingError> {
+    let ProcessImageRequest {
+        watermarks,
+        rotation,
+        encoder,
+        max_bytes,
+        keep_metadata,
+        output_profile,
+        background,
+        ..
+    } = parameters;
+    cancellation::ensure_not_cancelled()?;
+    ensure_output_within_limits(&resized, config)?;
+    let mut final_image = if let Some(rotation) = rotation {
+        debug!("Rotating image to {:?}", rotation);
+        ops::rot(&resized, rotation.clone().into())?
     } else {
-        resize_image(source, &size)?
+        resized
     };
 
     let image_width = final_image.get_width();
@@ -57,6 +372,7 @@ pub fn process_image(
         let watermark = &watermarks[i];
         debug!("Applying watermark: {:?}", watermark);
         let wm = VipsImage::new_from_buffer(&wm_buffer[..], "[access=VIPS_ACCESS_SEQUENTIAL]")?;
+        ensure_input_within_limits(&wm, config)?;
 
         let wm_width = wm.get_width();
         let wm_height = wm.get_height();
@@ -67,7 +383,8 @@ pub fn process_image(
             wm_width,
             wm_height,
             watermark.size,
-        )?;
+        )
+        .map_err(libvips::error::Error::from)?;
 
         let target_smaller = wm_width * wm_height > wm_target_width * wm_target_height;
         let wm = if target_smaller {
@@ -110,46 +427,912 @@ pub fn process_image(
         final_image = ops::composite2_with_opts(&final_image, &wm, ops::BlendMode::Over, &options)?;
     }
 
+    let format = match format {
+        ImageFormat::Auto => negotiate_format(accepted_formats, final_image.image_hasalpha()),
+        format => format,
+    };
+    // JPEG can't carry an alpha channel, while HEIC keeps it unless a background is explicitly requested
+    let final_image = match (format, background) {
+        (ImageFormat::Jpeg, _) | (ImageFormat::Heic, Some(_)) if final_image.image_hasalpha() => {
+            flatten(&final_image, background.unwrap_or(Colour::WHITE))?
+        }
+        _ => final_image,
+    };
+    // anything but sRGB is meaningless without the profile, so it's kept regardless of the metadata policy
+    let keep_icc = *output_profile != OutputProfile::Srgb;
+    let (keep, removal) = metadata::get_kept(*keep_metadata, keep_icc, format);
+    let encoding = Encoding {
+        format,
+        options: encoder,
+        keep,
+        removal,
+    };
     debug!("Encoding to: {}", format);
-    match format {
-        ImageFormat::Jpeg => {
+    let (bytes, used_quality) = match quality.unwrap_or_else(default_quality) {
+        Quality::Fixed(quality) => (encode(&final_image, &encoding, quality)?, quality),
+        Quality::Auto(target) => encode_with_auto_quality(&final_image, &encoding, target)?,
+    };
+    let (bytes, used_quality) = match max_bytes {
+        Some(max_bytes) if bytes.len() > *max_bytes as usize => {
+            encode_within_size(&final_image, &encoding, used_quality, *max_bytes, config)?
+        }
+        _ => (bytes, used_quality),
+    };
+    // there's no quality to determine when the encoder ignores it
+    let quality_determined = (matches!(quality, Some(Quality::Auto(_)))
+        && encoding.depends_on_quality())
+        || max_bytes.is_some();
+    Ok(ProcessedImage {
+        bytes,
+        format,
+        width: final_image.get_width(),
+        height: final_image.get_height(),
+        quality: quality_determined.then_some(used_quality),
+        trim_box: None,
+        passthrough: false,
+    })
+}
+
+pub fn inspect_image(buffer: &[u8]) -> std::result::Result<ImageInfo, ProcessingError> {
+    let image = VipsImage::new_from_buffer(buffer, "")?;
+    let entries = match rexif::parse_buffer_quiet(buffer).0 {
+        Ok(data) => data.entries,
+        Err(_) => Vec::new(),
+    };
+    let orientation = entries
+        .iter()
+        .find(|entry| entry.tag == rexif::ExifTag::Orientation)
+        .and_then(|entry| entry.value.to_i64(0));
+    let exif = INFO_EXIF_TAGS
+        .iter()
+        .filter_map(|(tag, name)| {
+            entries
+                .iter()
+                .find(|entry| entry.ifd.tag == *tag)
+                .map(|entry| {
+                    // the unknown tags have no readable value
+                    let value = match entry.tag {
+                        rexif::ExifTag::UnknownToMe => entry.value.to_string(),
+                        _ => entry.value_more_readable.to_string(),
+                    };
+                    (*name, value)
+                })
+        })
+        .collect();
+    let colour_space = match image.get_interpretation() {
+        Ok(interpretation) => format!("{:?}", interpretation),
+        Err(_) => String::from("Unknown"),
+    };
+    Ok(ImageInfo {
+        format: detect_source_format(buffer).to_string(),
+        width: image.get_width(),
+        height: image.get_height(),
+        has_alpha: image.image_hasalpha(),
+        orientation,
+        colour_space,
+        has_icc_profile: has_icc_profile(&image),
+        pages: image.get_n_pages(),
+        exif,
+    })
+}
+
+// Estimates the memory taken by the decoded source from its header, one byte per band of every pixel of all its
+// pages. The header is read with the options of the processing, the page and the density changing the dimensions,
+// but the pixels aren't decoded.
+pub fn estimate_decoded_size(
+    buffer: &[u8],
+    page: Option<u16>,
+    density: Option<u16>,
+) -> std::result::Result<u64, ProcessingError> {
+    let options = get_loader_options(detect_source_format(buffer), page, density, false);
+    let image = VipsImage::new_from_buffer(buffer, &options)?;
+    Ok(get_pixel_count(image.get_width(), image.get_height())
+        * u64::from(image.get_bands().unsigned_abs())
+        * u64::from(image.get_n_pages().max(1).unsigned_abs()))
+}
+
+pub fn analyze_image(
+    buffer: Vec<u8>,
+    config: &Configuration,
+) -> std::result::Result<analysis::ImageAnalysis, ProcessingError> {
+    let image = load_upright(&buffer[..], None, None, None, config)?;
+    let width = image.get_width();
+    let height = image.get_height();
+    let image = reduce(image, ANALYSIS_MAX_DIMENSION)?;
+    let image = if image.image_hasalpha() {
+        flatten(&image, Colour::WHITE)?
+    } else {
+        image
+    };
+    let grayscale = ops::colourspace(&image, ops::Interpretation::BW)?;
+    let grayscale = ops::cast(&grayscale, ops::BandFormat::Uchar)?;
+
+    let histogram: Vec<u32> = ops::hist_find(&grayscale)?
+        .image_write_to_memory()
+        .chunks_exact(4)
+        .map(|count| u32::from_ne_bytes([count[0], count[1], count[2], count[3]]))
+        .collect();
+    let kernel = VipsImage::image_new_matrix_from_array(3, 3, &LAPLACIAN_KERNEL)?;
+    let options = ops::ConvOptions {
+        precision: ops::Precision::Float,
+        ..ops::ConvOptions::default()
+    };
+    let laplacian = ops::conv_with_opts(&grayscale, &kernel, &options)?;
+    let sharpness = ops::deviate(&laplacian)?.powi(2);
+
+    Ok(analysis::ImageAnalysis::new(
+        width,
+        height,
+        sharpness,
+        &histogram,
+        ops::deviate(&grayscale)?,
+    ))
+}
+
+// The BlurHash or the ThumbHash, base64 encoded, of the requested page. Both are computed from a version of the image
+// reduced to the size accepted by ThumbHash, which is plenty for the few components they keep.
+pub fn compute_placeholder(
+    buffer: Vec<u8>,
+    parameters: ProcessImageRequest,
+    config: &Configuration,
+) -> std::result::Result<Placeholder, ProcessingError> {
+    let image = load_upright(
+        &buffer[..],
+        parameters.page,
+        parameters.density,
+        parameters.rotation.clone(),
+        config,
+    )?;
+    let width = image.get_width();
+    let height = image.get_height();
+    let image = reduce(image, placeholder::THUMBHASH_MAX_DIMENSION)?;
+    let image = match parameters.output {
+        OutputMode::Blurhash if image.image_hasalpha() => {
+            flatten(&image, parameters.background.unwrap_or(Colour::WHITE))?
+        }
+        _ => image,
+    };
+    let pixels = get_rgba_pixels(&image)?;
+    let pixels_width = image.get_width() as usize;
+    let pixels_height = image.get_height() as usize;
+    let hash = match parameters.output {
+        OutputMode::Thumbhash => placeholder::encode_base64(&placeholder::thumbhash(
+            pixels_width,
+            pixels_height,
+            &pixels,
+        )),
+        _ => placeholder::blurhash(
+            pixels_width,
+            pixels_height,
+            &pixels,
+            BLURHASH_COMPONENTS.0,
+            BLURHASH_COMPONENTS.1,
+        ),
+    };
+    Ok(Placeholder {
+        hash,
+        width,
+        height,
+    })
+}
+
+// The dominant colours of the requested page, computed from a reduced version of the image.
+pub fn compute_palette(
+    buffer: Vec<u8>,
+    parameters: ProcessImageRequest,
+    config: &Configuration,
+) -> std::result::Result<Vec<palette::PaletteColour>, ProcessingError> {
+    let image = load_upright(
+        &buffer[..],
+        parameters.page,
+        parameters.density,
+        parameters.rotation.clone(),
+        config,
+    )?;
+    let image = reduce(image, PALETTE_MAX_DIMENSION)?;
+    let pixels = get_rgba_pixels(&image)?;
+    Ok(palette::extract_palette(
+        &pixels,
+        parameters
+            .palette_size
+            .unwrap_or(palette::DEFAULT_PALETTE_SIZE),
+    ))
+}
+
+// The perceptual hash of the requested page, once upright, so that the hash doesn't depend on how the image is stored.
+pub fn compute_perceptual_hash(
+    buffer: Vec<u8>,
+    parameters: ProcessImageRequest,
+    config: &Configuration,
+) -> std::result::Result<u64, ProcessingError> {
+    let image = load_upright(
+        &buffer[..],
+        parameters.page,
+        parameters.density,
+        parameters.rotation.clone(),
+        config,
+    )?;
+    let algorithm = parameters.hash_algorithm;
+    let image = if image.image_hasalpha() {
+        flatten(&image, Colour::WHITE)?
+    } else {
+        image
+    };
+    let grayscale = ops::colourspace(&image, ops::Interpretation::BW)?;
+    let grayscale = ops::cast(&grayscale, ops::BandFormat::Uchar)?;
+    let (width, height) = perceptual_hash::get_sample_size(algorithm);
+    let sample = stretch(grayscale, width as i32, height as i32)?;
+    Ok(perceptual_hash::compute(
+        algorithm,
+        &sample.image_write_to_memory(),
+    ))
+}
+
+// Compares the images upright and flattened on white, the second one being resized to the dimensions of the first
+// one, itself reduced to at most `DIFF_MAX_DIMENSION`. Images with different aspect ratios get stretched rather than
+// cropped, so that the whole of both is compared. The heatmap is PNG encoded as it's only meaningful losslessly.
+pub fn compare_images(
+    first: Vec<u8>,
+    second: Vec<u8>,
+    threshold: u8,
+    with_heatmap: bool,
+    config: &Configuration,
+) -> std::result::Result<(diff::ImageDiff, Option<Vec<u8>>), ProcessingError> {
+    let first = load_upright(&first[..], None, None, None, config)?;
+    let first = reduce(first, DIFF_MAX_DIMENSION)?;
+    let width = first.get_width();
+    let height = first.get_height();
+    let second = load_upright(&second[..], None, None, None, config)?;
+    let second = stretch(second, width, height)?;
+
+    let first = get_rgb_pixels(&first)?;
+    let second = get_rgb_pixels(&second)?;
+    let image_diff = diff::compare(width, height, &first, &second, threshold);
+    let heatmap = if with_heatmap {
+        cancellation::ensure_not_cancelled()?;
+        let heatmap = VipsImage::new_from_memory(
+            &diff::heatmap(&first, &second),
+            width,
+            height,
+            3,
+            ops::BandFormat::Uchar,
+        )?;
+        Some(ops::pngsave_buffer(&heatmap)?)
+    } else {
+        None
+    };
+    Ok((image_diff, heatmap))
+}
+
+// Composes the images on the collage grid, each one resized to cover its cell and cropped around its centre. The
+// cells are flattened on the background of the layout, which also fills the gutter and the empty cells.
+pub fn compose_collage(
+    buffers: Vec<Vec<u8>>,
+    grid: collage::Grid,
+    areas: Vec<collage::CellArea>,
+    parameters: CollageRequest,
+    accepted_formats: Vec<ImageFormat>,
+    config: &Configuration,
+) -> std::result::Result<ProcessedImage, ProcessingError> {
+    let background = parameters.layout.background.unwrap_or(Colour::WHITE);
+    let mut canvas: Option<VipsImage> = None;
+    for (buffer, area) in buffers.iter().zip(&areas) {
+        cancellation::ensure_not_cancelled()?;
+        let cell = load_upright(buffer, None, None, None, config)?;
+        let cell = cover(cell, area.width, area.height)?;
+        let cell = if cell.image_hasalpha() {
+            flatten(&cell, background)?
+        } else {
+            cell
+        };
+        let cell = ops::colourspace(&cell, ops::Interpretation::Srgb)?;
+        let cell = ops::cast(&cell, ops::BandFormat::Uchar)?;
+        canvas = Some(match canvas {
+            None => {
+                let options = ops::EmbedOptions {
+                    extend: ops::Extend::Background,
+                    background: background.to_vips_background(),
+                };
+                ops::embed_with_opts(
+                    &cell,
+                    area.left,
+                    area.top,
+                    grid.width,
+                    grid.height,
+                    &options,
+                )?
+            }
+            Some(canvas) => ops::insert(&canvas, &cell, area.left, area.top)?,
+        });
+    }
+    let canvas = canvas
+        .ok_or_else(|| InvalidParameterError::new("cells", "at least one cell is required"))?;
+    cancellation::ensure_not_cancelled()?;
+    ensure_output_within_limits(&canvas, config)?;
+
+    let format = match parameters.format {
+        ImageFormat::Auto => negotiate_format(&accepted_formats, false),
+        format => format,
+    };
+    // the canvas carries the metadata of the first cell, which doesn't describe the collage
+    let (keep, removal) = metadata::get_kept(None, false, format);
+    let encoding = Encoding {
+        format,
+        options: &parameters.encoder,
+        keep,
+        removal,
+    };
+    debug!("Encoding collage to: {}", format);
+    let (bytes, used_quality) = match parameters.quality.unwrap_or_else(default_quality) {
+        Quality::Fixed(quality) => (encode(&canvas, &encoding, quality)?, quality),
+        Quality::Auto(target) => encode_with_auto_quality(&canvas, &encoding, target)?,
+    };
+    Ok(ProcessedImage {
+        bytes,
+        format,
+        width: grid.width,
+        height: grid.height,
+        quality: (matches!(parameters.quality, Some(Quality::Auto(_)))
+            && encoding.depends_on_quality())
+        .then_some(used_quality),
+        trim_box: None,
+        passthrough: false,
+    })
+}
+
+// Resizes the image so that it covers the area, cropping what overflows on both sides of the centre.
+fn cover(image: VipsImage, width: i32, height: i32) -> Result<VipsImage> {
+    let scale = (f64::from(width) / f64::from(image.get_width()))
+        .max(f64::from(height) / f64::from(image.get_height()));
+    let resized = ops::resize(&image, scale)?;
+    // the rounding of the resize can leave the image a pixel short, which gets filled with its edge
+    let options = ops::GravityOptions {
+        extend: ops::Extend::Copy,
+        ..ops::GravityOptions::default()
+    };
+    ops::gravity_with_opts(
+        &resized,
+        ops::CompassDirection::Centre,
+        width,
+        height,
+        &options,
+    )
+}
+
+// Resizes the image to exactly the given dimensions, whatever its aspect ratio.
+fn stretch(image: VipsImage, width: i32, height: i32) -> Result<VipsImage> {
+    let options = ops::ResizeOptions {
+        vscale: f64::from(height) / f64::from(image.get_height()),
+        ..ops::ResizeOptions::default()
+    };
+    let resized = ops::resize_with_opts(
+        &image,
+        f64::from(width) / f64::from(image.get_width()),
+        &options,
+    )?;
+    // as for `cover`, the rounding of the resize can leave the image a pixel off
+    let options = ops::GravityOptions {
+        extend: ops::Extend::Copy,
+        ..ops::GravityOptions::default()
+    };
+    ops::gravity_with_opts(
+        &resized,
+        ops::CompassDirection::Centre,
+        width,
+        height,
+        &options,
+    )
+}
+
+// Decodes the requested page upright and in sRGB, for the outputs describing the image rather than encoding it.
+fn load_upright(
+    buffer: &[u8],
+    page: Option<u16>,
+    density: Option<u16>,
+    rotation: Option<Rotation>,
+    config: &Configuration,
+) -> std::result::Result<VipsImage, ProcessingError> {
+    let options = get_loader_options(detect_source_format(buffer), page, density, false);
+    let source = VipsImage::new_from_buffer(buffer, &options)?;
+    ensure_input_within_limits(&source, config)?;
+    let source = convert_to_output_profile(source, OutputProfile::Srgb, config)?;
+    let image = ops::autorot(&source)?;
+    match rotation {
+        Some(rotation) => Ok(ops::rot(&image, rotation.into())?),
+        None => Ok(image),
+    }
+}
+
+// libvips only decodes the pixels once they're needed, so images that would take too much memory can be refused from
+// their header. Compressed formats such as PNG can hold huge dimensions within a few bytes, which `max_file_size`
+// doesn't catch.
+fn ensure_input_within_limits(
+    image: &VipsImage,
+    config: &Configuration,
+) -> std::result::Result<(), ProcessingError> {
+    let width = image.get_width();
+    let height = image.get_height();
+    if config
+        .max_input_dimension
+        .is_some_and(|max| width.max(height).unsigned_abs() > max)
+    {
+        return Err(ProcessingError::PixelLimitExceeded(
+            "max_input_dimension",
+            width,
+            height,
+        ));
+    }
+    if config
+        .max_input_pixels
+        .is_some_and(|max| get_pixel_count(width, height) > max)
+    {
+        return Err(ProcessingError::PixelLimitExceeded(
+            "max_input_pixels",
+            width,
+            height,
+        ));
+    }
+    Ok(())
+}
+
+fn ensure_output_within_limits(
+    image: &VipsImage,
+    config: &Configuration,
+) -> std::result::Result<(), ProcessingError> {
+    let width = image.get_width();
+    let height = image.get_height();
+    if config
+        .max_output_pixels
+        .is_some_and(|max| get_pixel_count(width, height) > max)
+    {
+        return Err(ProcessingError::PixelLimitExceeded(
+            "max_output_pixels",
+            width,
+            height,
+        ));
+    }
+    Ok(())
+}
+
+fn get_pixel_count(width: i32, height: i32) -> u64 {
+    u64::from(width.unsigned_abs()) * u64::from(height.unsigned_abs())
+}
+
+// Downsizes the image so that it fits within `max_dimension` on both sides.
+fn reduce(image: VipsImage, max_dimension: i32) -> Result<VipsImage> {
+    let scale = f64::from(max_dimension) / f64::from(image.get_width().max(image.get_height()));
+    if scale < 1.0 {
+        ops::resize(&image, scale)
+    } else {
+        Ok(image)
+    }
+}
+
+// The image flattened on white as 8-bit sRGB pixels, row after row.
+fn get_rgb_pixels(image: &VipsImage) -> Result<Vec<u8>> {
+    let image = ops::colourspace(image, ops::Interpretation::Srgb)?;
+    let image = if image.image_hasalpha() {
+        flatten(&image, Colour::WHITE)?
+    } else {
+        image
+    };
+    Ok(ops::cast(&image, ops::BandFormat::Uchar)?.image_write_to_memory())
+}
+
+// The image as 8-bit sRGB pixels with an alpha channel, row after row.
+fn get_rgba_pixels(image: &VipsImage) -> Result<Vec<u8>> {
+    let image = ops::colourspace(image, ops::Interpretation::Srgb)?;
+    let image = ops::cast(&image, ops::BandFormat::Uchar)?;
+    let image = if image.image_hasalpha() {
+        image
+    } else {
+        ops::bandjoin_const(&image, &mut [255.0])?
+    };
+    Ok(image.image_write_to_memory())
+}
+
+fn get_colour_bands(image: &VipsImage) -> i32 {
+    if image.image_hasalpha() {
+        image.get_bands() - 1
+    } else {
+        image.get_bands()
+    }
+}
+
+fn flatten(image: &VipsImage, background: Colour) -> Result<VipsImage> {
+    debug!("Flattening the alpha channel over {:?}", background);
+    let options = ops::FlattenOptions {
+        background: background.to_vips_background_for_bands(get_colour_bands(image)),
+        ..ops::FlattenOptions::default()
+    };
+    ops::flatten_with_opts(image, &options)
+}
+
+impl fmt::Display for TrimBox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            self.left, self.top, self.width, self.height
+        )
+    }
+}
+
+// Crops the borders similar to the background, which defaults to the colour of the top left pixel. Images made of the
+// background only are left untouched.
+fn trim_borders(image: VipsImage, trim: &Trim) -> Result<(VipsImage, Option<TrimBox>)> {
+    let bands = get_colour_bands(&image);
+    let background = match trim.background {
+        Some(colour) => colour.to_vips_background_for_bands(bands),
+        None => {
+            let mut pixel = ops::getpoint(&image, 0, 0)?;
+            pixel.truncate(bands as usize);
+            pixel
+        }
+    };
+    let options = ops::FindTrimOptions {
+        threshold: trim.threshold,
+        background,
+        ..ops::FindTrimOptions::default()
+    };
+    let (left, top, width, height) = ops::find_trim_with_opts(&image, &options)?;
+    if width == 0 || height == 0 {
+        debug!("Nothing left to keep once trimmed, the image is kept as is");
+        return Ok((image, None));
+    }
+    debug!(
+        "Trimming image to {}x{} from left: {}, top: {}",
+        width, height, left, top
+    );
+    let trimmed = ops::extract_area(&image, left, top, width, height)?;
+    Ok((
+        trimmed,
+        Some(TrimBox {
+            left,
+            top,
+            width,
+            height,
+        }),
+    ))
+}
+
+// Converts the image to the output profile through its embedded ICC profile. CMYK images without a profile are
+// converted through the `cmyk_fallback_profile`, while the other images without one are taken to sRGB first, which
+// gives the greyscale ones the bands the sRGB profile expects.
+fn convert_to_output_profile(
+    image: VipsImage,
+    output_profile: OutputProfile,
+    config: &Configuration,
+) -> Result<VipsImage> {
+    let is_cmyk = matches!(image.get_interpretation(), Ok(ops::Interpretation::Cmyk));
+    let has_profile = has_icc_profile(&image);
+    if !is_cmyk && !has_profile && output_profile == OutputProfile::Srgb {
+        return Ok(image);
+    }
+    let image = if !is_cmyk && !has_profile {
+        ops::colourspace(&image, ops::Interpretation::Srgb)?
+    } else {
+        image
+    };
+    let input_profile = if is_cmyk {
+        config
+            .cmyk_fallback_profile
+            .clone()
+            .unwrap_or_else(|| String::from("cmyk"))
+    } else {
+        String::from("srgb")
+    };
+    debug!(
+        "Converting image to the {} profile, fallback input profile: {}",
+        output_profile, input_profile
+    );
+    let options = ops::IccTransformOptions {
+        embedded: true,
+        input_profile: Some(input_profile),
+        intent: ops::Intent::Relative,
+        ..ops::IccTransformOptions::default()
+    };
+    ops::icc_transform_with_opts(&image, &output_profile.to_string(), &options)
+}
+
+// Reading a field that isn't set fails, which is how its absence is told.
+fn has_icc_profile(image: &VipsImage) -> bool {
+    image.get_as_string("icc-profile-data").is_ok()
+}
+
+// Looks for the highest quality below the one that came out too big, which keeps the encoded image within `max_bytes`.
+// When even the minimum quality is too big, the image is downscaled step by step, as far as `max_bytes_min_scale`
+// allows. The images whose encoding doesn't depend on the quality are only downscaled.
+fn encode_within_size(
+    image: &VipsImage,
+    encoding: &Encoding,
+    too_big_quality: i32,
+    max_bytes: u32,
+    config: &Configuration,
+) -> std::result::Result<(Vec<u8>, i32), ProcessingError> {
+    let min_quality = if encoding.depends_on_quality() {
+        config
+            .max_bytes_min_quality
+            .unwrap_or(DEFAULT_MAX_BYTES_MIN_QUALITY)
+            .min(too_big_quality)
+    } else {
+        too_big_quality
+    };
+    let min_scale = config.max_bytes_min_scale.unwrap_or(1.0).clamp(0.1, 1.0);
+
+    let encoded = search_quality(image, encoding, min_quality, too_big_quality - 1, max_bytes)?;
+    if let Some(encoded) = encoded {
+        return Ok(encoded);
+    }
+    let mut scale = 1.0;
+    while scale > min_scale {
+        scale = (scale * MAX_BYTES_DOWNSCALE_STEP).max(min_scale);
+        debug!(
+            "Image doesn't fit in {} bytes, downscaling it by {}",
+            max_bytes, scale
+        );
+        let downscaled = ops::resize(image, scale)?;
+        let encoded = search_quality(
+            &downscaled,
+            encoding,
+            min_quality,
+            too_big_quality,
+            max_bytes,
+        )?;
+        if let Some(encoded) = encoded {
+            return Ok(encoded);
+        }
+    }
+    Err(ProcessingError::TargetSizeUnreachable(max_bytes))
+}
+
+// Binary search of the highest quality within the bounds whose output fits in `max_bytes`.
+fn search_quality(
+    image: &VipsImage,
+    encoding: &Encoding,
+    min_quality: i32,
+    max_quality: i32,
+    max_bytes: u32,
+) -> std::result::Result<Option<(Vec<u8>, i32)>, ProcessingError> {
+    let (mut low, mut high) = (min_quality, max_quality);
+    let mut best = None;
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let encoded = encode(image, encoding, quality)?;
+        debug!(
+            "Encoded with quality {} to {} bytes",
+            quality,
+            encoded.len()
+        );
+        if encoded.len() <= max_bytes as usize {
+            best = Some((encoded, quality));
+            low = quality + 1;
+        } else {
+            high = quality - 1;
+        }
+    }
+    Ok(best)
+}
+
+// Encodes the image with increasing qualities and keeps the first, hence the smallest, output whose structural
+// similarity to the image reaches the target. The highest candidate is used when none of them does, as well as when the
+// encoding ignores the quality, there being nothing to search then.
+fn encode_with_auto_quality(
+    image: &VipsImage,
+    encoding: &Encoding,
+    target: f64,
+) -> std::result::Result<(Vec<u8>, i32), ProcessingError> {
+    let highest = AUTO_QUALITY_CANDIDATES[AUTO_QUALITY_CANDIDATES.len() - 1];
+    if !encoding.depends_on_quality() {
+        return Ok((encode(image, encoding, highest)?, highest));
+    }
+    let reference = get_luminance(image)?;
+    let mut encoded = Vec::new();
+    for quality in AUTO_QUALITY_CANDIDATES {
+        encoded = encode(image, encoding, quality)?;
+        let decoded = VipsImage::new_from_buffer(&encoded[..], "")?;
+        let similarity = get_structural_similarity(&reference, &get_luminance(&decoded)?)?;
+        debug!(
+            "Encoded with quality {} to {} bytes, structural similarity: {}",
+            quality,
+            encoded.len(),
+            similarity
+        );
+        if similarity >= target {
+            return Ok((encoded, quality));
+        }
+    }
+    Ok((encoded, highest))
+}
+
+// The luminance as a float image, transparent areas being flattened over white.
+fn get_luminance(image: &VipsImage) -> Result<VipsImage> {
+    let flattened = if image.image_hasalpha() {
+        flatten(image, Colour::WHITE)?
+    } else {
+        ops::copy(image)?
+    };
+    let grayscale = ops::colourspace(&flattened, ops::Interpretation::BW)?;
+    ops::cast(&grayscale, ops::BandFormat::Float)
+}
+
+// Mean SSIM of two luminance images of the same size, using a gaussian window.
+fn get_structural_similarity(x: &VipsImage, y: &VipsImage) -> Result<f64> {
+    let blur = |image: &VipsImage| {
+        let options = ops::GaussblurOptions {
+            precision: ops::Precision::Float,
+            ..ops::GaussblurOptions::default()
+        };
+        ops::gaussblur_with_opts(image, SSIM_SIGMA, &options)
+    };
+    let mu_x = blur(x)?;
+    let mu_y = blur(y)?;
+    let mu_x_sq = ops::multiply(&mu_x, &mu_x)?;
+    let mu_y_sq = ops::multiply(&mu_y, &mu_y)?;
+    let mu_xy = ops::multiply(&mu_x, &mu_y)?;
+    let sigma_x_sq = ops::subtract(&blur(&ops::multiply(x, x)?)?, &mu_x_sq)?;
+    let sigma_y_sq = ops::subtract(&blur(&ops::multiply(y, y)?)?, &mu_y_sq)?;
+    let sigma_xy = ops::subtract(&blur(&ops::multiply(x, y)?)?, &mu_xy)?;
+
+    let numerator = ops::multiply(
+        &ops::linear(&mu_xy, &mut [2.0], &mut [SSIM_C1])?,
+        &ops::linear(&sigma_xy, &mut [2.0], &mut [SSIM_C2])?,
+    )?;
+    let denominator = ops::multiply(
+        &ops::linear(&ops::add(&mu_x_sq, &mu_y_sq)?, &mut [1.0], &mut [SSIM_C1])?,
+        &ops::linear(
+            &ops::add(&sigma_x_sq, &sigma_y_sq)?,
+            &mut [1.0],
+            &mut [SSIM_C2],
+        )?,
+    )?;
+    ops::avg(&ops::divide(&numerator, &denominator)?)
+}
+
+// Every encoding is a checkpoint of the cancellation, as the quality searches encode the image over and over. The image
+// is killed when the processing gets cancelled meanwhile, which stops the encoding along with the lazy pipeline behind
+// it, e.g. the shrinking of a thumbnail.
+fn encode(
+    image: &VipsImage,
+    encoding: &Encoding,
+    quality: i32,
+) -> std::result::Result<Vec<u8>, ProcessingError> {
+    cancellation::ensure_not_cancelled()?;
+    let Encoding {
+        format,
+        options: encoder,
+        keep,
+        removal,
+    } = *encoding;
+    let kill = cancellation::kill_on_cancel(image);
+    let encoded = match format {
+        ImageFormat::Jpeg | ImageFormat::Auto => {
+            let progressive = encoder.jpeg.progressive.unwrap_or(true);
             let options = ops::JpegsaveBufferOptions {
                 q: quality,
                 background: vec![255.0],
-                keep: ops::ForeignKeep::None,
+                keep,
                 optimize_coding: true,
-                optimize_scans: true,
-                interlace: true,
+                optimize_scans: progressive,
+                interlace: progressive,
+                trellis_quant: encoder.jpeg.trellis.unwrap_or(false),
+                subsample_mode: encoder
+                    .jpeg
+                    .chroma_subsampling
+                    .map(ops::ForeignSubsample::from)
+                    .unwrap_or(ops::ForeignSubsample::Auto),
                 ..ops::JpegsaveBufferOptions::default()
             };
-            ops::jpegsave_buffer_with_opts(&final_image, &options)
+            ops::jpegsave_buffer_with_opts(image, &options)
         }
         ImageFormat::Webp => {
             let options = ops::WebpsaveBufferOptions {
                 q: quality,
-                keep: ops::ForeignKeep::None,
-                effort: 2,
+                keep,
+                effort: encoder.webp.effort.map(i32::from).unwrap_or(2),
+                lossless: encoder.webp.lossless.unwrap_or(false),
+                near_lossless: encoder.webp.near_lossless.unwrap_or(false),
+                smart_subsample: encoder.webp.smart_subsample.unwrap_or(false),
                 ..ops::WebpsaveBufferOptions::default()
             };
-            ops::webpsave_buffer_with_opts(&final_image, &options)
+            ops::webpsave_buffer_with_opts(image, &options)
         }
         ImageFormat::Png => {
+            let defaults = ops::PngsaveBufferOptions::default();
             let options = ops::PngsaveBufferOptions {
                 q: quality,
-                keep: ops::ForeignKeep::None,
-                bitdepth: 8,
-                ..ops::PngsaveBufferOptions::default()
+                keep,
+                bitdepth: encoder.png.bitdepth(),
+                palette: encoder.png.palette.unwrap_or(false),
+                dither: encoder.png.dither.unwrap_or(defaults.dither),
+                ..defaults
             };
-            ops::pngsave_buffer_with_opts(&final_image, &options)
+            ops::pngsave_buffer_with_opts(image, &options)
         }
-        ImageFormat::Heic => {
+        ImageFormat::Heic | ImageFormat::Avif => {
+            let defaults = ops::HeifsaveBufferOptions::default();
+            let compression = match format {
+                ImageFormat::Avif => ops::ForeignHeifCompression::Av1,
+                _ => encoder
+                    .heic
+                    .compression
+                    .map(ops::ForeignHeifCompression::from)
+                    .unwrap_or(defaults.compression),
+            };
             let options = ops::HeifsaveBufferOptions {
                 q: quality,
-                keep: ops::ForeignKeep::None,
-                ..ops::HeifsaveBufferOptions::default()
+                keep,
+                compression,
+                effort: encoder
+                    .heic
+                    .effort
+                    .map(i32::from)
+                    .unwrap_or(defaults.effort),
+                ..defaults
             };
-            ops::heifsave_buffer_with_opts(&final_image, &options)
+            ops::heifsave_buffer_with_opts(image, &options)
         }
+    };
+    drop(kill);
+    // a killed image fails its encoding with a libvips error
+    cancellation::ensure_not_cancelled()?;
+    let encoded = encoded?;
+    metadata::remove(encoded, format, removal).ok_or(ProcessingError::MetadataRemovalFailed(format))
+}
+
+// Decodes the image straight at the dimensions `resize_image` would give to the upright source, letting the JPEG,
+// WebP and HEIC loaders shrink it while decoding. The EXIF orientation is applied and the embedded profile is
+// converted to sRGB along the way, the same way `convert_to_output_profile` does.
+fn load_thumbnail(
+    buffer: &[u8],
+    source: &VipsImage,
+    orientation: Option<i64>,
+    size: &Size,
+    loader_options: String,
+) -> Result<VipsImage> {
+    let (width, height) = match orientation {
+        // these orientations transpose the stored image
+        Some(5..=8) => (source.get_height(), source.get_width()),
+        _ => (source.get_width(), source.get_height()),
+    };
+    let (target_width, target_height) = get_resized_size(width, height, size)?;
+    debug!(
+        "Shrinking image on load. Original size: {}x{}. Final size: {}x{}",
+        width, height, target_width, target_height
+    );
+    let mut options = ops::ThumbnailBufferOptions {
+        option_string: Some(loader_options),
+        height: target_height,
+        size: ops::Size::Force,
+        ..ops::ThumbnailBufferOptions::default()
+    };
+    if has_icc_profile(source) {
+        options.input_profile = Some(String::from("srgb"));
+        options.output_profile = Some(OutputProfile::Srgb.to_string());
+        options.intent = ops::Intent::Relative;
+    }
+    ops::thumbnail_buffer_with_opts(buffer, target_width, &options)
+}
+
+// The dimensions `ops::resize` gives when scaling to the target width, libvips rounding the height where
+// `get_target_size` truncates it.
+fn get_resized_size(width: i32, height: i32, size: &Size) -> Result<(i32, i32)> {
+    let (target_width, _) = get_target_size(width, height, size)?;
+    let scale = f64::from(target_width) / f64::from(width);
+    Ok((
+        target_width,
+        ((f64::from(height) * scale).round() as i32).max(1),
+    ))
+}
+
+fn get_orientation(buffer: &[u8]) -> Option<i64> {
+    match rexif::parse_buffer_quiet(buffer).0 {
+        Ok(data) => data
+            .entries
+            .into_iter()
+            .find(|entry| entry.tag == rexif::ExifTag::Orientation)
+            .and_then(|entry| entry.value.to_i64(0)),
+        Err(_) => None,
     }
 }
 
@@ -172,3 +1355,443 @@ fn resize_image(img: VipsImage, size: &Size) -> Result<VipsImage> {
 
     ops::resize(&img, f64::from(target_width) / f64::from(original_width))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+    use libvips::VipsApp;
+
+    lazy_static! {
+        static ref VIPS_APP: VipsApp =
+            VipsApp::new("dali tests", false).expect("Can't initialize Vips");
+    }
+
+    fn get_perceptual_hash(buffer: Vec<u8>, algorithm: &str) -> u64 {
+        lazy_static::initialize(&VIPS_APP);
+        let parameters = serde_qs::from_str(&format!(
+            "image_address=fixture&output=Phash&hash_algorithm={}",
+            algorithm
+        ))
+        .unwrap();
+        let config = Configuration::for_tests(serde_json::json!({}));
+        compute_perceptual_hash(buffer, parameters, &config).expect("Cannot hash the image")
+    }
+
+    fn reencode(buffer: &[u8], scale: f64, suffix: &str) -> Vec<u8> {
+        lazy_static::initialize(&VIPS_APP);
+        let image = VipsImage::new_from_buffer(buffer, "").unwrap();
+        let image = ops::autorot(&image).unwrap();
+        let image = ops::resize(&image, scale).unwrap();
+        image.image_write_to_buffer(suffix).unwrap()
+    }
+
+    #[test]
+    fn test_perceptual_hash_survives_reencoding() {
+        let original = std::fs::read("tests/resources/img-test").unwrap();
+        for algorithm in ["Ahash", "Dhash", "Phash"] {
+            let hash = get_perceptual_hash(original.clone(), algorithm);
+            assert_eq!(hash, get_perceptual_hash(original.clone(), algorithm));
+            for (scale, suffix) in [(1.0, ".webp[Q=40]"), (0.5, ".png"), (0.7, ".jpg[Q=30]")] {
+                let reencoded = get_perceptual_hash(reencode(&original, scale, suffix), algorithm);
+                assert!(
+                    (hash ^ reencoded).count_ones() <= 8,
+                    "{} {}",
+                    algorithm,
+                    suffix
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_perceptual_hash_follows_orientation() {
+        // stored rotated, with an EXIF orientation of 8
+        let rotated = std::fs::read("tests/resources/exif").unwrap();
+        let upright = reencode(&rotated, 1.0, ".jpg[Q=90,strip]");
+        let other = std::fs::read("tests/resources/img-test").unwrap();
+        for algorithm in ["Ahash", "Dhash", "Phash"] {
+            let hash = get_perceptual_hash(rotated.clone(), algorithm);
+            assert!((hash ^ get_perceptual_hash(upright.clone(), algorithm)).count_ones() <= 8);
+            assert!((hash ^ get_perceptual_hash(other.clone(), algorithm)).count_ones() > 16);
+        }
+    }
+
+    #[test]
+    fn test_compare_images() {
+        lazy_static::initialize(&VIPS_APP);
+        let config = Configuration::for_tests(serde_json::json!({}));
+        let original = std::fs::read("tests/resources/img-test").unwrap();
+        let (identical, heatmap) =
+            compare_images(original.clone(), original.clone(), 16, false, &config).unwrap();
+        assert_eq!(identical.mean_absolute_difference, 0.0);
+        assert_eq!(identical.psnr, None);
+        assert!(heatmap.is_none());
+
+        // a smaller and heavily compressed copy is compared at the dimensions of the original
+        let reencoded = reencode(&original, 0.5, ".jpg[Q=20]");
+        let (image_diff, heatmap) =
+            compare_images(original.clone(), reencoded, 16, true, &config).unwrap();
+        let source = VipsImage::new_from_buffer(&original[..], "").unwrap();
+        assert_eq!(
+            (image_diff.width, image_diff.height),
+            (source.get_width(), source.get_height())
+        );
+        assert!(image_diff.mean_absolute_difference > 0.0);
+        assert!(image_diff
+            .psnr
+            .is_some_and(|psnr| psnr > 20.0 && psnr < 50.0));
+        let heatmap = VipsImage::new_from_buffer(&heatmap.unwrap()[..], "").unwrap();
+        assert_eq!(heatmap.get_width(), image_diff.width);
+
+        let other = std::fs::read("tests/resources/exif").unwrap();
+        let (different, heatmap) =
+            compare_images(original, other.clone(), 16, true, &config).unwrap();
+        assert!(different.changed_percentage > image_diff.changed_percentage);
+        assert!(heatmap.is_some());
+
+        // scales whose rounding would leave the second image a pixel off
+        for scale in [0.37, 0.61, 1.13] {
+            let rescaled = reencode(&other, scale, ".png");
+            let (image_diff, heatmap) =
+                compare_images(rescaled, other.clone(), 16, true, &config).unwrap();
+            let heatmap = VipsImage::new_from_buffer(&heatmap.unwrap()[..], "").unwrap();
+            assert_eq!(
+                (heatmap.get_width(), heatmap.get_height()),
+                (image_diff.width, image_diff.height)
+            );
+        }
+    }
+
+    #[test]
+    fn test_flatten_greyscale_with_alpha() {
+        lazy_static::initialize(&VIPS_APP);
+        let lena = VipsImage::new_from_buffer(&std::fs::read("tests/resources/lena").unwrap(), "")
+            .unwrap();
+        let grey = ops::colourspace(&lena, ops::Interpretation::BW).unwrap();
+        let grey_with_alpha = ops::bandjoin_const(&grey, &mut [128.0]).unwrap();
+        assert!(grey_with_alpha.image_hasalpha());
+        let background = "336699".parse().unwrap();
+        assert_eq!(
+            flatten(&grey_with_alpha, background).unwrap().get_bands(),
+            1
+        );
+
+        // a greyscale PNG with transparency requested as JPEG
+        let processed = process_image(
+            ops::pngsave_buffer(&grey_with_alpha).unwrap(),
+            Vec::new(),
+            serde_qs::from_str("image_address=grey&format=Jpeg&background=336699").unwrap(),
+            Vec::new(),
+            &Configuration::for_tests(serde_json::json!({})),
+        )
+        .unwrap();
+        assert_eq!(processed.format, ImageFormat::Jpeg);
+        assert_eq!(
+            (processed.width, processed.height),
+            (grey_with_alpha.get_width(), grey_with_alpha.get_height())
+        );
+    }
+
+    #[test]
+    fn test_passthrough_only_when_untransformed() {
+        lazy_static::initialize(&VIPS_APP);
+        let original = std::fs::read("tests/resources/lena").unwrap();
+        let config = Configuration::for_tests(serde_json::json!({"passthrough_enabled": true}));
+        let is_passthrough = |query: &str| {
+            process_image(
+                original.clone(),
+                Vec::new(),
+                serde_qs::from_str(query).unwrap(),
+                Vec::new(),
+                &config,
+            )
+            .unwrap()
+            .passthrough
+        };
+
+        assert!(is_passthrough("image_address=lena&format=Jpeg"));
+        assert!(!is_passthrough(
+            "image_address=lena&format=Jpeg&encoder[jpeg][progressive]=false"
+        ));
+        assert!(!is_passthrough(
+            "image_address=lena&format=Jpeg&background=336699"
+        ));
+
+        // the defaults of the deployment only apply to the images that get encoded
+        let config = Configuration::for_tests(serde_json::json!({
+            "passthrough_enabled": true,
+            "encoder_defaults": {"jpeg": {"trellis": true}},
+        }));
+        let processed = process_image(
+            original.clone(),
+            Vec::new(),
+            serde_qs::from_str("image_address=lena&format=Jpeg").unwrap(),
+            Vec::new(),
+            &config,
+        )
+        .unwrap();
+        assert!(processed.passthrough);
+
+        let config = Configuration::for_tests(serde_json::json!({
+            "passthrough_enabled": true,
+            "max_output_pixels": 1000,
+        }));
+        assert!(matches!(
+            process_image(
+                original.clone(),
+                Vec::new(),
+                serde_qs::from_str("image_address=lena&format=Jpeg").unwrap(),
+                Vec::new(),
+                &config,
+            ),
+            Err(ProcessingError::PixelLimitExceeded(
+                "max_output_pixels",
+                _,
+                _
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_convert_to_output_profile() {
+        lazy_static::initialize(&VIPS_APP);
+        let config = Configuration::for_tests(serde_json::json!({}));
+        let lena = VipsImage::new_from_buffer(&std::fs::read("tests/resources/lena").unwrap(), "")
+            .unwrap();
+        // saved without any profile, so that the conversions rely on the fallbacks
+        let without_profile = |image: &VipsImage| {
+            let options = ops::JpegsaveBufferOptions {
+                keep: ops::ForeignKeep::None,
+                ..ops::JpegsaveBufferOptions::default()
+            };
+            let buffer = ops::jpegsave_buffer_with_opts(image, &options).unwrap();
+            VipsImage::new_from_buffer(&buffer, "").unwrap()
+        };
+
+        let untouched =
+            convert_to_output_profile(without_profile(&lena), OutputProfile::Srgb, &config)
+                .unwrap();
+        assert!(!has_icc_profile(&untouched));
+        let p3 =
+            convert_to_output_profile(without_profile(&lena), OutputProfile::P3, &config).unwrap();
+        assert_eq!(p3.get_bands(), 3);
+        assert!(has_icc_profile(&p3));
+
+        let cmyk = without_profile(&ops::colourspace(&lena, ops::Interpretation::Cmyk).unwrap());
+        assert_eq!(cmyk.get_bands(), 4);
+        let converted = convert_to_output_profile(cmyk, OutputProfile::Srgb, &config).unwrap();
+        assert_eq!(converted.get_bands(), 3);
+        assert!(matches!(
+            converted.get_interpretation(),
+            Ok(ops::Interpretation::Srgb)
+        ));
+
+        let grey = without_profile(&ops::colourspace(&lena, ops::Interpretation::BW).unwrap());
+        assert_eq!(grey.get_bands(), 1);
+        let converted = convert_to_output_profile(grey, OutputProfile::P3, &config).unwrap();
+        assert_eq!(converted.get_bands(), 3);
+        assert!(has_icc_profile(&converted));
+    }
+
+    #[test]
+    fn test_keep_metadata_strips_gps() {
+        lazy_static::initialize(&VIPS_APP);
+        // lena along with a make, a copyright and a location in its EXIF
+        let original = std::fs::read("tests/resources/gps").unwrap();
+        let get_exif_tags = |buffer: &[u8]| match rexif::parse_buffer_quiet(buffer).0 {
+            Ok(data) => data
+                .entries
+                .into_iter()
+                .map(|entry| entry.tag)
+                .collect::<Vec<_>>(),
+            Err(_) => Vec::new(),
+        };
+        let gps_tags = [
+            rexif::ExifTag::GPSLatitudeRef,
+            rexif::ExifTag::GPSLatitude,
+            rexif::ExifTag::GPSLongitudeRef,
+            rexif::ExifTag::GPSLongitude,
+        ];
+        let source_tags = get_exif_tags(&original);
+        assert!(gps_tags.iter().all(|tag| source_tags.contains(tag)));
+
+        let processed = process_image(
+            original,
+            Vec::new(),
+            serde_qs::from_str("image_address=gps&format=Jpeg&keep_metadata=AllButGps").unwrap(),
+            Vec::new(),
+            &Configuration::for_tests(serde_json::json!({})),
+        )
+        .unwrap();
+        let processed_tags = get_exif_tags(&processed.bytes);
+        assert!(processed_tags.contains(&rexif::ExifTag::Copyright));
+        assert!(!processed_tags.iter().any(|tag| gps_tags.contains(tag)));
+    }
+
+    #[test]
+    fn test_max_bytes() {
+        lazy_static::initialize(&VIPS_APP);
+        // 1000x563 pixels
+        let original = std::fs::read("tests/resources/img-test").unwrap();
+        let process = |query: &str, settings: serde_json::Value| {
+            process_image(
+                original.clone(),
+                Vec::new(),
+                serde_qs::from_str(query).unwrap(),
+                Vec::new(),
+                &Configuration::for_tests(settings),
+            )
+        };
+
+        let fitted = process(
+            "image_address=img-test&format=Jpeg&max_bytes=60000",
+            serde_json::json!({}),
+        )
+        .unwrap();
+        assert!(fitted.bytes.len() <= 60000);
+        assert!(fitted
+            .quality
+            .is_some_and(|quality| quality >= DEFAULT_MAX_BYTES_MIN_QUALITY));
+        assert_eq!((fitted.width, fitted.height), (1000, 563));
+
+        // lossless PNG only gets smaller once downscaled
+        let downscaled = process(
+            "image_address=img-test&format=Png&max_bytes=200000",
+            serde_json::json!({"max_bytes_min_scale": 0.1}),
+        )
+        .unwrap();
+        assert!(downscaled.bytes.len() <= 200000);
+        assert!(downscaled.width < 1000);
+        assert_eq!(downscaled.quality, Some(75));
+
+        assert!(matches!(
+            process(
+                "image_address=img-test&format=Jpeg&max_bytes=1000",
+                serde_json::json!({})
+            ),
+            Err(ProcessingError::TargetSizeUnreachable(1000))
+        ));
+    }
+
+    #[test]
+    fn test_auto_quality_of_lossless_png() {
+        lazy_static::initialize(&VIPS_APP);
+        let original = std::fs::read("tests/resources/img-test").unwrap();
+        let processed = process_image(
+            original,
+            Vec::new(),
+            serde_qs::from_str("image_address=img-test&format=Png&quality=auto:0.95").unwrap(),
+            Vec::new(),
+            &Configuration::for_tests(serde_json::json!({})),
+        )
+        .unwrap();
+        assert_eq!(processed.format, ImageFormat::Png);
+        assert_eq!(processed.quality, None);
+    }
+
+    #[test]
+    fn test_pixel_limits() {
+        lazy_static::initialize(&VIPS_APP);
+        // 1000x563 pixels
+        let original = std::fs::read("tests/resources/img-test").unwrap();
+        let process = |query: &str, limits: serde_json::Value| {
+            process_image(
+                original.clone(),
+                Vec::new(),
+                serde_qs::from_str(query).unwrap(),
+                Vec::new(),
+                &Configuration::for_tests(limits),
+            )
+        };
+        let limit_of = |result: std::result::Result<ProcessedImage, ProcessingError>| match result {
+            Err(ProcessingError::PixelLimitExceeded(limit, _, _)) => Some(limit),
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(_) => None,
+        };
+        let query = "image_address=img-test";
+        let resized = "image_address=img-test&size[width]=100";
+        assert_eq!(
+            limit_of(process(
+                query,
+                serde_json::json!({"max_input_dimension": 999})
+            )),
+            Some("max_input_dimension")
+        );
+        assert_eq!(
+            limit_of(process(
+                query,
+                serde_json::json!({"max_input_pixels": 500000})
+            )),
+            Some("max_input_pixels")
+        );
+        assert_eq!(
+            limit_of(process(
+                query,
+                serde_json::json!({"max_input_pixels": 563000, "max_input_dimension": 1000})
+            )),
+            None
+        );
+        assert_eq!(
+            limit_of(process(
+                query,
+                serde_json::json!({"max_output_pixels": 10000})
+            )),
+            Some("max_output_pixels")
+        );
+        assert_eq!(
+            limit_of(process(
+                resized,
+                serde_json::json!({"max_output_pixels": 10000})
+            )),
+            None
+        );
+        assert_eq!(
+            limit_of(process(
+                resized,
+                serde_json::json!({"max_output_pixels": 5000})
+            )),
+            Some("max_output_pixels")
+        );
+    }
+
+    #[test]
+    fn test_shrink_on_load_keeps_dimensions() {
+        lazy_static::initialize(&VIPS_APP);
+        let highres = std::fs::read("tests/resources/highres").unwrap();
+        let fixtures = [
+            (
+                "img-test",
+                std::fs::read("tests/resources/img-test").unwrap(),
+            ),
+            // stored rotated, with an EXIF orientation of 8
+            ("exif", std::fs::read("tests/resources/exif").unwrap()),
+            ("highres webp", reencode(&highres, 1.0, ".webp")),
+            ("highres", highres),
+        ];
+        let sizes = [
+            (Some(300), None),
+            (None, Some(300)),
+            (Some(300), Some(300)),
+            (Some(1000), Some(100)),
+            (Some(20000), None),
+        ];
+        for (name, buffer) in &fixtures {
+            let source = VipsImage::new_from_buffer(buffer, "").unwrap();
+            let orientation = get_orientation(buffer);
+            for (width, height) in sizes {
+                let size = Size { width, height };
+                let resized = resize_image(ops::autorot(&source).unwrap(), &size).unwrap();
+                let thumbnail =
+                    load_thumbnail(buffer, &source, orientation, &size, String::new()).unwrap();
+                assert_eq!(
+                    (thumbnail.get_width(), thumbnail.get_height()),
+                    (resized.get_width(), resized.get_height()),
+                    "{} to {:?}",
+                    name,
+                    size
+                );
+            }
+        }
+    }
+}
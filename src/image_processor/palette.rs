@@ -0,0 +1,154 @@
+// (c) Copyright 2019-2026 OLX
+
+// Palette extraction through median cut: the distinct colours are recursively split at the weighted median of their
+// widest channel until the requested amount of boxes is reached, each box being represented by its average colour.
+
+use crate::commons::Colour;
+use std::collections::HashMap;
+
+pub const DEFAULT_PALETTE_SIZE: u8 = 5;
+pub const MAX_PALETTE_SIZE: u8 = 16;
+// pixels more transparent than this don't contribute to the palette
+const MIN_ALPHA: u8 = 128;
+
+pub struct PaletteColour {
+    pub colour: Colour,
+    // the share of the opaque pixels represented by the colour, from 0 to 1
+    pub share: f64,
+}
+
+// The colours are sorted by decreasing share, the first one being the dominant colour. Fully transparent images have
+// an empty palette.
+pub fn extract_palette(rgba: &[u8], size: u8) -> Vec<PaletteColour> {
+    let mut histogram: HashMap<[u8; 3], u64> = HashMap::new();
+    for pixel in rgba.chunks_exact(4).filter(|pixel| pixel[3] >= MIN_ALPHA) {
+        *histogram.entry([pixel[0], pixel[1], pixel[2]]).or_default() += 1;
+    }
+    if histogram.is_empty() {
+        return Vec::new();
+    }
+    let total: u64 = histogram.values().sum();
+
+    let mut boxes = vec![histogram.into_iter().collect::<Vec<_>>()];
+    while boxes.len() < usize::from(size) {
+        let Some((index, channel)) = boxes
+            .iter()
+            .enumerate()
+            .map(|(index, colours)| (index, get_widest_channel(colours)))
+            .filter(|(_, (_, range))| *range > 0)
+            .max_by_key(|(_, (_, range))| *range)
+            .map(|(index, (channel, _))| (index, channel))
+        else {
+            // every box holds a single colour, there's nothing left to split
+            break;
+        };
+        let mut colours = boxes.swap_remove(index);
+        colours.sort_unstable_by_key(|(colour, _)| (colour[channel], *colour));
+        let upper = colours.split_off(get_median_index(&colours));
+        boxes.push(colours);
+        boxes.push(upper);
+    }
+
+    let mut palette: Vec<PaletteColour> = boxes
+        .iter()
+        .map(|colours| {
+            let count: u64 = colours.iter().map(|(_, count)| count).sum();
+            PaletteColour {
+                colour: get_average(colours, count),
+                share: count as f64 / total as f64,
+            }
+        })
+        .collect();
+    palette.sort_by(|a, b| b.share.total_cmp(&a.share));
+    palette
+}
+
+// The channel with the largest spread of values, along with the spread.
+fn get_widest_channel(colours: &[([u8; 3], u64)]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let (min, max) = colours
+                .iter()
+                .fold((u8::MAX, u8::MIN), |(min, max), (colour, _)| {
+                    (min.min(colour[channel]), max.max(colour[channel]))
+                });
+            (channel, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap()
+}
+
+// The index splitting the sorted colours in two halves of similar population, both holding at least one colour.
+fn get_median_index(colours: &[([u8; 3], u64)]) -> usize {
+    let half: u64 = colours.iter().map(|(_, count)| count).sum::<u64>() / 2;
+    let mut population = 0;
+    let index = colours
+        .iter()
+        .position(|(_, count)| {
+            population += count;
+            population > half
+        })
+        .unwrap_or(0);
+    index.clamp(1, colours.len() - 1)
+}
+
+fn get_average(colours: &[([u8; 3], u64)], count: u64) -> Colour {
+    let sums = colours.iter().fold([0_u64; 3], |sums, (colour, weight)| {
+        [
+            sums[0] + u64::from(colour[0]) * weight,
+            sums[1] + u64::from(colour[1]) * weight,
+            sums[2] + u64::from(colour[2]) * weight,
+        ]
+    });
+    let [red, green, blue] = sums.map(|sum| ((sum + count / 2) / count) as u8);
+    Colour { red, green, blue }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_palette() {
+        let red = [255, 0, 0, 255];
+        let blue = [0, 0, 255, 255];
+        let transparent = [0, 255, 0, 0];
+        let rgba = [red.repeat(6), blue.repeat(2), transparent.repeat(8)].concat();
+
+        let palette = extract_palette(&rgba, 5);
+        // only two distinct opaque colours, they can't be split any further
+        assert_eq!(palette.len(), 2);
+        assert_eq!(
+            palette[0].colour,
+            Colour {
+                red: 255,
+                green: 0,
+                blue: 0
+            }
+        );
+        assert_eq!(palette[0].share, 0.75);
+        assert_eq!(
+            palette[1].colour,
+            Colour {
+                red: 0,
+                green: 0,
+                blue: 255
+            }
+        );
+        assert_eq!(palette[1].share, 0.25);
+
+        let palette = extract_palette(&rgba, 1);
+        assert_eq!(palette.len(), 1);
+        assert_eq!(
+            palette[0].colour,
+            Colour {
+                red: 191,
+                green: 0,
+                blue: 64
+            }
+        );
+        assert_eq!(palette[0].share, 1.0);
+
+        assert!(extract_palette(&transparent.repeat(4), 5).is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+// (c) Copyright 2019-2026 OLX
+
+// Serving the source bytes as they are, when the processing wouldn't change anything but the encoding. The metadata
+// is stripped from the JPEG segments and the PNG chunks directly, without decoding the image. The ICC profile is
+// always kept as the processed image would have been converted through it.
+
+use std::borrow::Cow;
+
+use crate::commons::{ImageFormat, KeepMetadata, SourceFormat};
+use crate::image_processor::metadata::{map_jpeg_segments, map_png_chunks, JPEG_ICC_SIGNATURE};
+
+const JPEG_APP2: u8 = 0xe2;
+// Adobe segment, telling how the colour channels are encoded
+const JPEG_APP14: u8 = 0xee;
+const JPEG_COM: u8 = 0xfe;
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 5] = [b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+// The source bytes without the metadata that the policy doesn't keep. Returns `None` when the source can't be served
+// as the requested format or when the policy can't be applied without re-encoding.
+pub fn get_original(
+    buffer: &[u8],
+    source_format: SourceFormat,
+    format: ImageFormat,
+    keep_metadata: Option<KeepMetadata>,
+) -> Option<Vec<u8>> {
+    if !matches!(
+        keep_metadata,
+        None | Some(KeepMetadata::None) | Some(KeepMetadata::Icc)
+    ) {
+        return None;
+    }
+    match (source_format, format) {
+        (SourceFormat::Jpeg, ImageFormat::Jpeg) => strip_jpeg_metadata(buffer),
+        (SourceFormat::Png, ImageFormat::Png) => strip_png_metadata(buffer),
+        _ => None,
+    }
+}
+
+// Drops the APPn segments but JFIF, ICC and Adobe ones, as well as the comments.
+fn strip_jpeg_metadata(buffer: &[u8]) -> Option<Vec<u8>> {
+    map_jpeg_segments(buffer, |marker, segment| {
+        let is_metadata = match marker {
+            JPEG_APP2 => !segment[4..].starts_with(JPEG_ICC_SIGNATURE),
+            JPEG_APP14 => false,
+            JPEG_COM => true,
+            // APP0 is the JFIF header
+            0xe1..=0xef => true,
+            _ => false,
+        };
+        (!is_metadata).then_some(Cow::Borrowed(segment))
+    })
+}
+
+// Drops the EXIF, the textual and the modification time chunks.
+fn strip_png_metadata(buffer: &[u8]) -> Option<Vec<u8>> {
+    map_png_chunks(buffer, |chunk_type, chunk| {
+        let is_metadata = PNG_METADATA_CHUNKS
+            .iter()
+            .any(|metadata| chunk_type == *metadata);
+        (!is_metadata).then_some(Cow::Borrowed(chunk))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_processor::metadata::{JPEG_SOI, PNG_SIGNATURE};
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let length = (payload.len() + 2) as u16;
+        [&[0xff, marker], &length.to_be_bytes()[..], payload].concat()
+    }
+
+    fn png_chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let length = data.len() as u32;
+        [&length.to_be_bytes()[..], chunk_type, data, &[0, 0, 0, 0]].concat()
+    }
+
+    #[test]
+    fn test_strip_jpeg_metadata() {
+        let jfif = jpeg_segment(0xe0, b"JFIF\0\x01\x01");
+        let exif = jpeg_segment(0xe1, b"Exif\0\0data");
+        let icc = jpeg_segment(0xe2, b"ICC_PROFILE\0\x01\x01profile");
+        let flashpix = jpeg_segment(0xe2, b"FPXR\0");
+        let comment = jpeg_segment(0xfe, b"comment");
+        let quantisation = jpeg_segment(0xdb, b"\0tables");
+        let scan = [
+            &jpeg_segment(0xda, b"\0scan")[..],
+            b"\xe1entropy coded\xff\xd9",
+        ]
+        .concat();
+        let source = [
+            &JPEG_SOI[..],
+            &jfif,
+            &exif,
+            &icc,
+            &flashpix,
+            &comment,
+            &quantisation,
+            &scan,
+        ]
+        .concat();
+        let expected = [&JPEG_SOI[..], &jfif, &icc, &quantisation, &scan].concat();
+        assert_eq!(strip_jpeg_metadata(&source), Some(expected));
+        assert_eq!(strip_jpeg_metadata(&source[..20]), None);
+        assert_eq!(strip_jpeg_metadata(b"GIF89a"), None);
+    }
+
+    #[test]
+    fn test_strip_png_metadata() {
+        let header = png_chunk(b"IHDR", &[0; 13]);
+        let icc = png_chunk(b"iCCP", b"sRGB\0\0profile");
+        let text = png_chunk(b"tEXt", b"Author\0someone");
+        let exif = png_chunk(b"eXIf", b"MM\0*");
+        let data = png_chunk(b"IDAT", b"pixels");
+        let end = png_chunk(b"IEND", b"");
+        let source = [&PNG_SIGNATURE[..], &header, &icc, &text, &exif, &data, &end].concat();
+        let expected = [&PNG_SIGNATURE[..], &header, &icc, &data, &end].concat();
+        assert_eq!(strip_png_metadata(&source), Some(expected));
+        assert_eq!(strip_png_metadata(&source[..source.len() - 4]), None);
+    }
+
+    #[test]
+    fn test_get_original() {
+        let source = [&PNG_SIGNATURE[..], &png_chunk(b"IEND", b"")].concat();
+        assert!(get_original(&source, SourceFormat::Png, ImageFormat::Png, None).is_some());
+        assert!(get_original(&source, SourceFormat::Png, ImageFormat::Jpeg, None).is_none());
+        assert!(get_original(
+            &source,
+            SourceFormat::Png,
+            ImageFormat::Png,
+            Some(KeepMetadata::Copyright)
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,121 @@
+// (c) Copyright 2019-2026 OLX
+
+// 64-bit perceptual hashes computed from a grayscale sample of the image, resized to a fixed size regardless of its
+// aspect ratio. Similar images have hashes with a small Hamming distance, whatever their format or encoding.
+
+use crate::commons::HashAlgorithm;
+use std::f64::consts::PI;
+
+const HASH_SIDE: usize = 8;
+// pHash keeps the lowest 8x8 frequencies of the DCT of a 32x32 sample
+const DCT_SAMPLE_SIDE: usize = 32;
+
+// The width and height of the grayscale sample expected by `compute`.
+pub fn get_sample_size(algorithm: HashAlgorithm) -> (usize, usize) {
+    match algorithm {
+        HashAlgorithm::Ahash => (HASH_SIDE, HASH_SIDE),
+        // one more column as every bit compares two neighbouring pixels
+        HashAlgorithm::Dhash => (HASH_SIDE + 1, HASH_SIDE),
+        HashAlgorithm::Phash => (DCT_SAMPLE_SIDE, DCT_SAMPLE_SIDE),
+    }
+}
+
+// The bits are set row after row, starting from the most significant one.
+pub fn compute(algorithm: HashAlgorithm, pixels: &[u8]) -> u64 {
+    match algorithm {
+        HashAlgorithm::Ahash => {
+            let values: Vec<f64> = pixels.iter().map(|pixel| f64::from(*pixel)).collect();
+            let mean = values.iter().sum::<f64>() / values.len() as f64;
+            to_bits(values.iter().map(|value| *value > mean))
+        }
+        HashAlgorithm::Dhash => to_bits(
+            pixels
+                .chunks_exact(HASH_SIDE + 1)
+                .flat_map(|row| row.windows(2).map(|pair| pair[1] > pair[0])),
+        ),
+        HashAlgorithm::Phash => {
+            let coefficients = get_low_frequencies(pixels);
+            // the DC term, the first one, is the average brightness and would skew the median of the frequencies
+            let mut sorted = coefficients[1..].to_vec();
+            sorted.sort_by(f64::total_cmp);
+            let median = sorted[sorted.len() / 2];
+            to_bits(coefficients.iter().map(|coefficient| *coefficient > median))
+        }
+    }
+}
+
+// The 8x8 lowest frequencies of the two-dimensional DCT-II of the sample, row after row.
+fn get_low_frequencies(pixels: &[u8]) -> Vec<f64> {
+    let cosines: Vec<Vec<f64>> = (0..HASH_SIDE)
+        .map(|frequency| {
+            (0..DCT_SAMPLE_SIDE)
+                .map(|position| {
+                    (PI * frequency as f64 * (2 * position + 1) as f64
+                        / (2 * DCT_SAMPLE_SIDE) as f64)
+                        .cos()
+                })
+                .collect()
+        })
+        .collect();
+    let mut coefficients = Vec::with_capacity(HASH_SIDE * HASH_SIDE);
+    for v in 0..HASH_SIDE {
+        for u in 0..HASH_SIDE {
+            let mut coefficient = 0.0;
+            for (y, row) in pixels.chunks_exact(DCT_SAMPLE_SIDE).enumerate() {
+                for (x, pixel) in row.iter().enumerate() {
+                    coefficient += f64::from(*pixel) * cosines[u][x] * cosines[v][y];
+                }
+            }
+            coefficients.push(coefficient);
+        }
+    }
+    coefficients
+}
+
+fn to_bits(bits: impl Iterator<Item = bool>) -> u64 {
+    bits.fold(0, |hash, bit| (hash << 1) | u64::from(bit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_pattern(side: usize, offset: u8) -> Vec<u8> {
+        (0..side * side)
+            .map(|index| {
+                ((index % side) * 7 + (index / side) * 13 + index * index % 31) as u8 % 200 + offset
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_average_hash() {
+        // left half black, right half white
+        let pixels: Vec<u8> = (0..64)
+            .map(|index| if index % 8 < 4 { 0 } else { 255 })
+            .collect();
+        assert_eq!(
+            compute(HashAlgorithm::Ahash, &pixels),
+            0x0f0f_0f0f_0f0f_0f0f
+        );
+    }
+
+    #[test]
+    fn test_difference_hash() {
+        let gradient: Vec<u8> = (0..72).map(|index| (index % 9) as u8 * 10).collect();
+        assert_eq!(compute(HashAlgorithm::Dhash, &gradient), u64::MAX);
+        let reversed: Vec<u8> = gradient.iter().map(|pixel| 255 - pixel).collect();
+        assert_eq!(compute(HashAlgorithm::Dhash, &reversed), 0);
+    }
+
+    #[test]
+    fn test_dct_hash_ignores_brightness() {
+        let pixels = get_pattern(DCT_SAMPLE_SIDE, 0);
+        let brighter = get_pattern(DCT_SAMPLE_SIDE, 40);
+        let hash = compute(HashAlgorithm::Phash, &pixels);
+        // only the DC term, the first bit, depends on the brightness
+        assert!((hash ^ compute(HashAlgorithm::Phash, &brighter)) & !(1 << 63) == 0);
+        // the median splits the 63 other frequencies
+        assert_eq!((hash & !(1 << 63)).count_ones(), 31);
+    }
+}
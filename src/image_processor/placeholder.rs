@@ -0,0 +1,296 @@
+// (c) Copyright 2019-2026 OLX
+
+// Compact placeholders computed from the RGBA pixels of a small version of the image, following the reference
+// implementations of BlurHash (https://blurha.sh) and ThumbHash (https://evanw.github.io/thumbhash).
+
+use std::f64::consts::PI;
+
+const BASE83_CHARACTERS: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+const BASE64_CHARACTERS: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// ThumbHash only encodes images fitting within 100x100 pixels.
+pub const THUMBHASH_MAX_DIMENSION: i32 = 100;
+
+// The alpha channel is ignored, transparent images have to be flattened beforehand.
+pub fn blurhash(
+    width: usize,
+    height: usize,
+    rgba: &[u8],
+    components_x: usize,
+    components_y: usize,
+) -> String {
+    let mut factors = Vec::with_capacity(components_x * components_y);
+    for j in 0..components_y {
+        for i in 0..components_x {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0; 3];
+            for y in 0..height {
+                let basis_y = (PI * j as f64 * y as f64 / height as f64).cos();
+                for x in 0..width {
+                    let basis = basis_y * (PI * i as f64 * x as f64 / width as f64).cos();
+                    let pixel = &rgba[4 * (y * width + x)..];
+                    for (channel, value) in factor.iter_mut().enumerate() {
+                        *value += basis * srgb_to_linear(pixel[channel]);
+                    }
+                }
+            }
+            let scale = normalisation / (width * height) as f64;
+            factors.push(factor.map(|value| value * scale));
+        }
+    }
+
+    let mut hash = String::with_capacity(4 + 2 * factors.len());
+    encode_base83(
+        ((components_x - 1) + (components_y - 1) * 9) as u32,
+        1,
+        &mut hash,
+    );
+    let (dc, ac) = factors.split_first().unwrap();
+    let maximum_value = if ac.is_empty() {
+        encode_base83(0, 1, &mut hash);
+        1.0
+    } else {
+        let actual_maximum = ac
+            .iter()
+            .flatten()
+            .fold(0.0_f64, |maximum, value| maximum.max(value.abs()));
+        let quantised = (actual_maximum * 166.0 - 0.5).floor().clamp(0.0, 82.0);
+        encode_base83(quantised as u32, 1, &mut hash);
+        (quantised + 1.0) / 166.0
+    };
+
+    let dc = dc.map(|value| u32::from(linear_to_srgb(value)));
+    encode_base83((dc[0] << 16) + (dc[1] << 8) + dc[2], 4, &mut hash);
+    for factor in ac {
+        let quantised = factor.map(|value| {
+            (sign_pow(value / maximum_value, 0.5) * 9.0 + 9.5)
+                .floor()
+                .clamp(0.0, 18.0) as u32
+        });
+        encode_base83(
+            quantised[0] * 19 * 19 + quantised[1] * 19 + quantised[2],
+            2,
+            &mut hash,
+        );
+    }
+    hash
+}
+
+// The image shouldn't exceed `THUMBHASH_MAX_DIMENSION` on any side.
+pub fn thumbhash(width: usize, height: usize, rgba: &[u8]) -> Vec<u8> {
+    let pixels = width * height;
+    let (mut average_r, mut average_g, mut average_b, mut average_a) = (0.0, 0.0, 0.0, 0.0);
+    for pixel in rgba.chunks_exact(4).take(pixels) {
+        let alpha = f64::from(pixel[3]) / 255.0;
+        average_r += alpha / 255.0 * f64::from(pixel[0]);
+        average_g += alpha / 255.0 * f64::from(pixel[1]);
+        average_b += alpha / 255.0 * f64::from(pixel[2]);
+        average_a += alpha;
+    }
+    if average_a > 0.0 {
+        average_r /= average_a;
+        average_g /= average_a;
+        average_b /= average_a;
+    }
+
+    let has_alpha = average_a < pixels as f64;
+    // fewer luminance bits are used when there's alpha
+    let luminance_limit = if has_alpha { 5.0 } else { 7.0 };
+    let longest_side = width.max(height) as f64;
+    let lx = round(luminance_limit * width as f64 / longest_side).max(1);
+    let ly = round(luminance_limit * height as f64 / longest_side).max(1);
+
+    // converts the pixels from RGBA to LPQA, composited atop the average colour
+    let mut l = Vec::with_capacity(pixels);
+    let mut p = Vec::with_capacity(pixels);
+    let mut q = Vec::with_capacity(pixels);
+    let mut a = Vec::with_capacity(pixels);
+    for pixel in rgba.chunks_exact(4).take(pixels) {
+        let alpha = f64::from(pixel[3]) / 255.0;
+        let r = average_r * (1.0 - alpha) + alpha / 255.0 * f64::from(pixel[0]);
+        let g = average_g * (1.0 - alpha) + alpha / 255.0 * f64::from(pixel[1]);
+        let b = average_b * (1.0 - alpha) + alpha / 255.0 * f64::from(pixel[2]);
+        l.push((r + g + b) / 3.0);
+        p.push((r + g) / 2.0 - b);
+        q.push(r - g);
+        a.push(alpha);
+    }
+
+    let (l_dc, l_ac, l_scale) = encode_channel(&l, width, height, lx.max(3), ly.max(3));
+    let (p_dc, p_ac, p_scale) = encode_channel(&p, width, height, 3, 3);
+    let (q_dc, q_ac, q_scale) = encode_channel(&q, width, height, 3, 3);
+
+    let is_landscape = width > height;
+    let header24 = round(63.0 * l_dc)
+        | (round(31.5 + 31.5 * p_dc) << 6)
+        | (round(31.5 + 31.5 * q_dc) << 12)
+        | (round(31.0 * l_scale) << 18)
+        | (u32::from(has_alpha) << 23);
+    let header16 = (if is_landscape { ly } else { lx })
+        | (round(63.0 * p_scale) << 3)
+        | (round(63.0 * q_scale) << 9)
+        | (u32::from(is_landscape) << 15);
+    let mut hash = vec![
+        header24 as u8,
+        (header24 >> 8) as u8,
+        (header24 >> 16) as u8,
+        header16 as u8,
+        (header16 >> 8) as u8,
+    ];
+
+    let mut channels = vec![l_ac, p_ac, q_ac];
+    if has_alpha {
+        let (a_dc, a_ac, a_scale) = encode_channel(&a, width, height, 5, 5);
+        hash.push((round(15.0 * a_dc) | (round(15.0 * a_scale) << 4)) as u8);
+        channels.push(a_ac);
+    }
+    for (index, factor) in channels.iter().flatten().enumerate() {
+        let nibble = (round(15.0 * factor) as u8) << ((index & 1) << 2);
+        if index & 1 == 0 {
+            hash.push(nibble);
+        } else {
+            *hash.last_mut().unwrap() |= nibble;
+        }
+    }
+    hash
+}
+
+pub fn encode_base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0_u32, |group, (index, byte)| {
+                group | (u32::from(*byte) << (16 - 8 * index))
+            });
+        for index in 0..4 {
+            if index <= chunk.len() {
+                let sextet = (group >> (18 - 6 * index)) & 0x3f;
+                encoded.push(BASE64_CHARACTERS[sextet as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// Returns the DC term, the AC terms normalised to [0, 1] and the scale they were normalised with.
+fn encode_channel(
+    channel: &[f64],
+    width: usize,
+    height: usize,
+    nx: u32,
+    ny: u32,
+) -> (f64, Vec<f64>, f64) {
+    let mut dc = 0.0;
+    let mut ac = Vec::new();
+    let mut scale = 0.0_f64;
+    let mut fx = vec![0.0; width];
+    for cy in 0..ny {
+        let mut cx = 0;
+        while cx * ny < nx * (ny - cy) {
+            for (x, value) in fx.iter_mut().enumerate() {
+                *value = (PI / width as f64 * f64::from(cx) * (x as f64 + 0.5)).cos();
+            }
+            let mut factor = 0.0;
+            for y in 0..height {
+                let fy = (PI / height as f64 * f64::from(cy) * (y as f64 + 0.5)).cos();
+                for x in 0..width {
+                    factor += channel[x + y * width] * fx[x] * fy;
+                }
+            }
+            factor /= (width * height) as f64;
+            if cx > 0 || cy > 0 {
+                ac.push(factor);
+                scale = scale.max(factor.abs());
+            } else {
+                dc = factor;
+            }
+            cx += 1;
+        }
+    }
+    if scale > 0.0 {
+        for value in ac.iter_mut() {
+            *value = 0.5 + 0.5 / scale * *value;
+        }
+    }
+    (dc, ac, scale)
+}
+
+fn encode_base83(value: u32, length: u32, hash: &mut String) {
+    for i in 1..=length {
+        let digit = (value / 83_u32.pow(length - i)) % 83;
+        hash.push(BASE83_CHARACTERS[digit as usize] as char);
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f64 {
+    let value = f64::from(value) / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f64) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.0031308 {
+        (value * 12.92 * 255.0 + 0.5) as u8
+    } else {
+        ((1.055 * value.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u8
+    }
+}
+
+fn sign_pow(value: f64, exponent: f64) -> f64 {
+    value.abs().powf(exponent).copysign(value)
+}
+
+// rounds halves up, as JavaScript's `Math.round` used by the reference implementation
+fn round(value: f64) -> u32 {
+    (value + 0.5).floor() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blurhash_of_plain_image() {
+        let white = [255; 4 * 8 * 6];
+        let hash = blurhash(8, 6, &white, 4, 3);
+        // the components, the AC scale, the average colour then two characters per AC term
+        assert_eq!(hash.len(), 1 + 1 + 4 + 2 * 11);
+        assert_eq!(&hash[..1], "L");
+        assert_eq!(&hash[2..6], "TSUA");
+        let black = [0, 0, 0, 255].repeat(16);
+        assert_eq!(blurhash(4, 4, &black, 1, 1), "000000");
+    }
+
+    #[test]
+    fn test_thumbhash_header() {
+        let white = [255; 4 * 4 * 4];
+        let hash = thumbhash(4, 4, &white);
+        // 27 luminance and 2 * 5 chrominance AC terms packed by two
+        assert_eq!(hash.len(), 5 + 19);
+        assert_eq!(hash[..5], [63, 8, 2, 7, 0]);
+
+        let transparent = [0; 4 * 10 * 5];
+        let hash = thumbhash(10, 5, &transparent);
+        // the alpha flag and the orientation of the image
+        assert_eq!(hash[2] & 0x80, 0x80);
+        assert_eq!(hash[4] & 0x80, 0x80);
+    }
+
+    #[test]
+    fn test_encode_base64() {
+        assert_eq!(encode_base64(b"Man"), "TWFu");
+        assert_eq!(encode_base64(b"Ma"), "TWE=");
+        assert_eq!(encode_base64(b"M"), "TQ==");
+        assert_eq!(encode_base64(&[0xfb, 0xff]), "+/8=");
+    }
+}
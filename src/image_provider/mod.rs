@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use async_trait::async_trait;
+use log::error;
 
 #[cfg(feature = "reqwest")]
 use crate::image_provider::reqwest::client::ReqwestImageProvider;
@@ -12,6 +13,9 @@ use self::s3::s3::S3ImageProvider;
 pub mod reqwest;
 pub mod s3;
 
+// The payloads are pre-allocated from the length reported upstream, which can be anything when no `max_file_size` is set.
+const MAX_PREALLOCATED_BYTES: u64 = 16 * 1024 * 1024;
+
 #[cfg(not(any(feature = "reqwest", feature = "s3")))]
 compile_error!("only 's3' is available as an extra feature for the image storage service");
 
@@ -25,6 +29,23 @@ pub trait ImageProvider: Send + Sync {
     async fn get_file(&self, resource: &str, config: &Configuration) -> Result<ImageResponse, ImageProcessingError>;
 }
 
+// Rejects the files whose reported length already exceeds `max_file_size`, so that they cost neither bandwidth nor memory,
+// and otherwise returns how many bytes to pre-allocate for their payload. The limit is still enforced while streaming, as
+// the length can be missing or wrong.
+pub fn get_payload_capacity(resource: &str, content_length: Option<u64>, config: &Configuration) -> Result<usize, ImageProcessingError> {
+    let content_length = content_length.unwrap_or(0);
+    if let Some(max_size) = config.max_file_size {
+        if content_length > u64::from(max_size) {
+            error!(
+                "the image '{}' of {} bytes exceeds the maximum allowed size of {} bytes",
+                resource, content_length, max_size
+            );
+            return Err(ImageProcessingError::FileSizeExceeded(max_size));
+        }
+    }
+    Ok(content_length.min(MAX_PREALLOCATED_BYTES) as usize)
+}
+
 #[allow(unreachable_code)]
 pub async fn create_image_provider(config: &Configuration) -> Box<dyn ImageProvider> {
     #[cfg(feature = "s3")]
@@ -37,3 +58,25 @@ pub async fn create_image_provider(config: &Configuration) -> Box<dyn ImageProvi
         return Box::new(ReqwestImageProvider::new(config).await);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_payload_capacity() {
+        let config = Configuration::for_tests(serde_json::json!({"max_file_size": 1000}));
+        assert_eq!(get_payload_capacity("a.jpg", Some(1000), &config).unwrap(), 1000);
+        assert_eq!(get_payload_capacity("a.jpg", None, &config).unwrap(), 0);
+        assert!(matches!(
+            get_payload_capacity("a.jpg", Some(1001), &config),
+            Err(ImageProcessingError::FileSizeExceeded(1000))
+        ));
+
+        let unlimited = Configuration::for_tests(serde_json::json!({}));
+        assert_eq!(
+            get_payload_capacity("a.jpg", Some(u64::MAX), &unlimited).unwrap(),
+            MAX_PREALLOCATED_BYTES as usize
+        );
+    }
+}
@@ -13,7 +13,7 @@ pub mod client {
         ClientReturnedErrorStatusCode, ImageDownloadFailed, ImageDownloadTimedOut,
         InvalidResourceUriProvided, FileSizeExceeded
     };
-    use crate::image_provider::{ImageProvider, ImageResponse};
+    use crate::image_provider::{get_payload_capacity, ImageProvider, ImageResponse};
     use crate::routes::image::ImageProcessingError;
 
     pub struct ReqwestImageProvider {
@@ -84,10 +84,10 @@ pub mod client {
                 })
                 .collect();
             if status.is_success() {
-                let response_length = response.content_length().unwrap_or(0);
+                let capacity = get_payload_capacity(resource, response.content_length(), config)?;
                 let mut stream = response.bytes_stream();
                 let mut total_bytes = 0;
-                let mut binary_payload: Vec<u8> = Vec::with_capacity(response_length as usize);
+                let mut binary_payload: Vec<u8> = Vec::with_capacity(capacity);
                 while let Some(bytes) = stream.try_next().await.map_err(|e| {
                     error!(
                         "failed to read the binary payload of the image '{}'. error: {}",
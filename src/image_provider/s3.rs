@@ -15,6 +15,7 @@ pub mod s3 {
     use crate::commons::config::Configuration;
     use crate::image_provider::ImageResponse;
     use crate::image_provider::{
+        get_payload_capacity,
         ImageProcessingError::{
             self, ClientReturnedErrorStatusCode, ImageDownloadFailed, ImageDownloadTimedOut,
             InvalidResourceUriProvided, FileSizeExceeded,
@@ -141,8 +142,9 @@ pub mod s3 {
                     })
                     .collect(),
             };
-            let response_length = result.content_length().unwrap_or(0).max(0) as usize;
-            let mut binary_payload: Vec<u8> = Vec::with_capacity(response_length);
+            let content_length = result.content_length().and_then(|length| u64::try_from(length).ok());
+            let capacity = get_payload_capacity(resource, content_length, config)?;
+            let mut binary_payload: Vec<u8> = Vec::with_capacity(capacity);
             let mut total_bytes = 0;
             while let Some(bytes) = result.body.try_next().await.map_err(|e| {
                 error!(
@@ -4,16 +4,19 @@ use std::sync::Arc;
 use std::time::Duration;
 use std::time::SystemTime;
 
-use axum::extract::Request;
+use axum::extract::{Request, State};
 use axum::http::StatusCode;
 use axum::middleware::{self, Next};
-use axum::response::IntoResponse;
+use axum::response::{IntoResponse, Response};
 use axum::{routing::get, Router};
 use image_provider::{create_image_provider, ImageProvider};
 use libvips::VipsApp;
 use moka::future::Cache;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 
 use commons::config::Configuration;
+use routes::admission::Admission;
+use routes::image::ImageProcessingError;
 use routes::metric::HTTP_DURATION;
 
 #[cfg(feature = "opentelemetry")]
@@ -24,6 +27,10 @@ mod image_processor;
 mod image_provider;
 mod routes;
 
+// the decoders run on the processing threads, which get as much stack as the main thread rather than the 2 MiB of the
+// spawned threads
+const PROCESSING_THREAD_STACK_SIZE: usize = 8 * 1024 * 1024;
+
 #[tokio::main]
 async fn main() {
     let config = Configuration::new().expect("Failed to load application configuration.");
@@ -77,6 +84,20 @@ fn create_vips_app(config: &Configuration) -> Option<VipsApp> {
     Some(app)
 }
 
+// The threads running the processing jobs, each of them driving up to `vips_threads` threads of libvips.
+fn create_processing_pool(config: &Configuration) -> ThreadPool {
+    let processing_threads = config
+        .processing_threads
+        .map(usize::from)
+        .unwrap_or_else(num_cpus::get);
+    ThreadPoolBuilder::new()
+        .num_threads(processing_threads)
+        .thread_name(|i| format!("dali-processing-{}", i))
+        .stack_size(PROCESSING_THREAD_STACK_SIZE)
+        .build()
+        .expect("Cannot create the processing thread pool")
+}
+
 fn create_watermarks_cache(config: &Configuration) -> Cache<String, Arc<Vec<u8>>> {
     let cache_size = config.watermark_cache_size.unwrap_or(15);
     let ttl = Duration::from_secs(config.watermark_cache_ttl_seconds.unwrap_or(28800)); // 8 hours
@@ -99,6 +120,8 @@ async fn start_management_server(config: &Configuration) {
 #[derive(Clone)]
 pub struct AppState {
     vips_app: Arc<VipsApp>,
+    admission: Arc<Admission>,
+    processing_pool: Arc<ThreadPool>,
     image_provider: Arc<Box<dyn ImageProvider>>,
     config: Arc<Configuration>,
     watermark_cache: Cache<String, Arc<Vec<u8>>>,
@@ -173,9 +196,28 @@ async fn measure_request_handling_duration(
     Ok(res)
 }
 
+// Bounds the whole handling of the requests, the download included. The processing of a request past its deadline is
+// cancelled as the handler gets dropped.
+async fn enforce_request_deadline(
+    State(config): State<Arc<Configuration>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    match config.request_deadline_millis {
+        Some(deadline) => {
+            tokio::time::timeout(Duration::from_millis(u64::from(deadline)), next.run(req))
+                .await
+                .unwrap_or_else(|_| ImageProcessingError::RequestDeadlineExceeded.into_response())
+        }
+        None => next.run(req).await,
+    }
+}
+
 async fn start_main_server(config: &Configuration) {
     let app_state = AppState {
         vips_app: Arc::new(create_vips_app(&config).unwrap()),
+        admission: Arc::new(Admission::new(config)),
+        processing_pool: Arc::new(create_processing_pool(config)),
         image_provider: Arc::new(create_image_provider(&config).await),
         config: Arc::new(config.clone()),
         watermark_cache: create_watermarks_cache(&config),
@@ -183,7 +225,15 @@ async fn start_main_server(config: &Configuration) {
 
     let app = Router::new()
         .route("/", get(routes::image::process_image))
-        .with_state(app_state)
+        .route("/info", get(routes::info::get_info))
+        .route("/analyze", get(routes::analyze::analyze_image))
+        .route("/collage", get(routes::collage::compose_collage))
+        .route("/diff", get(routes::diff::diff_images))
+        .with_state(app_state.clone())
+        .layer(middleware::from_fn_with_state(
+            app_state.config,
+            enforce_request_deadline,
+        ))
         .layer(middleware::from_fn(measure_request_handling_duration));
 
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.app_port))
@@ -0,0 +1,224 @@
+// (c) Copyright 2019-2026 OLX
+
+// Admission control of the processing jobs. At most `max_processing_jobs` of them run at once, the following ones wait
+// for their turn as long as fewer than `max_queued_processing_jobs` are waiting already. The others are shed right
+// away, so that the latency stays bounded for the admitted ones during traffic spikes. The admitted jobs can besides be
+// limited by the memory their sources take once decoded, within `processing_memory_budget`.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::commons::config::Configuration;
+use crate::routes::image::ImageProcessingError;
+use crate::routes::metric::{
+    MEMORY_BUDGET_WAIT_DURATION, PROCESSING_JOBS_IN_FLIGHT, PROCESSING_JOBS_QUEUED,
+};
+
+// the memory budget is counted in KiB, as the semaphores can only acquire up to `u32::MAX` permits at once
+const MEMORY_UNIT: u64 = 1024;
+
+pub struct Admission {
+    permits: Option<Arc<Semaphore>>,
+    max_queued: Option<usize>,
+    queued: AtomicUsize,
+    memory: Option<(Arc<Semaphore>, u32)>,
+}
+
+// Held by the job until its processing is over, even when nobody waits for its result anymore.
+pub struct AdmissionPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        PROCESSING_JOBS_IN_FLIGHT.dec();
+    }
+}
+
+// Held by the job until its processing is over, like the admission permit.
+pub struct MemoryReservation {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+// Counts the job as waiting until it's dropped, whether it got admitted or the request was abandoned meanwhile.
+struct QueuedJob<'a>(&'a AtomicUsize);
+
+impl<'a> QueuedJob<'a> {
+    fn new(queued: &'a AtomicUsize) -> (Self, usize) {
+        PROCESSING_JOBS_QUEUED.inc();
+        (QueuedJob(queued), queued.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Drop for QueuedJob<'_> {
+    fn drop(&mut self) {
+        PROCESSING_JOBS_QUEUED.dec();
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Admission {
+    pub fn new(config: &Configuration) -> Self {
+        Admission {
+            permits: config
+                .max_processing_jobs
+                .map(|max_jobs| Arc::new(Semaphore::new(max_jobs as usize))),
+            max_queued: config
+                .max_queued_processing_jobs
+                .map(|max_queued| max_queued as usize),
+            queued: AtomicUsize::new(0),
+            memory: config.processing_memory_budget.map(|budget| {
+                // a budget below the unit still lets one image through at a time
+                let units = u32::try_from(budget / MEMORY_UNIT)
+                    .unwrap_or(u32::MAX)
+                    .max(1);
+                (Arc::new(Semaphore::new(units as usize)), units)
+            }),
+        }
+    }
+
+    pub fn has_memory_budget(&self) -> bool {
+        self.memory.is_some()
+    }
+
+    // Waits until the decoded image fits within the memory budget. An image larger than the whole budget gets all of
+    // it, running alone rather than never.
+    pub async fn reserve_memory(&self, bytes: u64) -> MemoryReservation {
+        let Some((memory, budget)) = &self.memory else {
+            return MemoryReservation { _permit: None };
+        };
+        let units =
+            u32::try_from(bytes.div_ceil(MEMORY_UNIT)).map_or(*budget, |units| units.min(*budget));
+        let start = Instant::now();
+        let permit = memory
+            .clone()
+            .acquire_many_owned(units)
+            .await
+            .expect("The memory budget semaphore is never closed");
+        MEMORY_BUDGET_WAIT_DURATION.observe(start.elapsed().as_secs_f64());
+        MemoryReservation {
+            _permit: Some(permit),
+        }
+    }
+
+    pub async fn admit(&self) -> Result<AdmissionPermit, ImageProcessingError> {
+        let permit = match &self.permits {
+            Some(permits) => Some(match permits.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    let (_queued_job, already_queued) = QueuedJob::new(&self.queued);
+                    if self
+                        .max_queued
+                        .is_some_and(|max_queued| already_queued >= max_queued)
+                    {
+                        return Err(ImageProcessingError::ProcessingQueueFull);
+                    }
+                    permits
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("The processing semaphore is never closed")
+                }
+            }),
+            None => None,
+        };
+        PROCESSING_JOBS_IN_FLIGHT.inc();
+        Ok(AdmissionPermit { _permit: permit })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{header, StatusCode};
+    use axum::response::IntoResponse;
+    use std::time::Duration;
+
+    fn get_admission(max_jobs: u32, max_queued: u32) -> Admission {
+        Admission::new(&Configuration::for_tests(serde_json::json!({
+            "max_processing_jobs": max_jobs,
+            "max_queued_processing_jobs": max_queued,
+        })))
+    }
+
+    #[tokio::test]
+    async fn test_admit_sheds_without_queue() {
+        let admission = get_admission(1, 0);
+        let permit = admission.admit().await.unwrap();
+        let Err(error) = admission.admit().await else {
+            panic!("the second job should have been shed");
+        };
+        assert!(matches!(error, ImageProcessingError::ProcessingQueueFull));
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        drop(permit);
+        assert!(admission.admit().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_admit_queues_within_limit() {
+        let admission = Arc::new(get_admission(1, 1));
+        let permit = admission.admit().await.unwrap();
+        let queued = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.admit().await.is_ok() }
+        });
+        while admission.queued.load(Ordering::Relaxed) == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert!(matches!(
+            admission.admit().await,
+            Err(ImageProcessingError::ProcessingQueueFull)
+        ));
+
+        drop(permit);
+        assert!(queued.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_memory_units() {
+        let get_budget = |budget: u64| {
+            Admission::new(&Configuration::for_tests(serde_json::json!({
+                "processing_memory_budget": budget,
+            })))
+        };
+        let get_units = |admission: &Admission| admission.memory.as_ref().unwrap().1;
+        assert_eq!(get_units(&get_budget(10 * MEMORY_UNIT + 10)), 10);
+        assert_eq!(get_units(&get_budget(10)), 1);
+        assert_eq!(get_units(&get_budget(u64::MAX)), u32::MAX);
+
+        let admission = get_budget(10 * MEMORY_UNIT);
+        let available = || admission.memory.as_ref().unwrap().0.available_permits();
+        // partial units are rounded up
+        let reservation = admission.reserve_memory(MEMORY_UNIT + 1).await;
+        assert_eq!(available(), 8);
+        drop(reservation);
+        assert_eq!(available(), 10);
+        assert!(get_budget(u64::MAX)
+            .reserve_memory(u64::MAX)
+            .await
+            ._permit
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_memory_larger_than_budget() {
+        let admission = Admission::new(&Configuration::for_tests(serde_json::json!({
+            "processing_memory_budget": 10 * MEMORY_UNIT,
+        })));
+        // the image takes the whole budget rather than waiting forever
+        let larger = admission.reserve_memory(100 * MEMORY_UNIT).await;
+        let waiting =
+            tokio::time::timeout(Duration::from_millis(50), admission.reserve_memory(1)).await;
+        assert!(waiting.is_err());
+        drop(larger);
+        let smaller =
+            tokio::time::timeout(Duration::from_secs(1), admission.reserve_memory(1)).await;
+        assert!(smaller.is_ok());
+    }
+}
@@ -0,0 +1,46 @@
+use axum::{body::Body, extract::State, http::Response};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::{
+    commons::SourceImageRequest,
+    image_processor,
+    routes::image::{
+        ensure_source_format_enabled, json_response, log_fetch_duration, run_processing,
+        ImageProcessingError, ProcessImageRequestExtractor,
+    },
+    AppState,
+};
+
+// Scores the quality of the source image, for warning about blurry, badly exposed or blank photos.
+pub async fn analyze_image(
+    State(AppState {
+        vips_app,
+        admission,
+        processing_pool,
+        image_provider,
+        config,
+        ..
+    }): State<AppState>,
+    ProcessImageRequestExtractor(params): ProcessImageRequestExtractor<SourceImageRequest>,
+) -> Result<Response<Body>, ImageProcessingError> {
+    let now = SystemTime::now();
+    let main_img = image_provider
+        .get_file(&params.image_address, &config)
+        .await?;
+    ensure_source_format_enabled(&main_img.bytes, &config)?;
+    log_fetch_duration(now);
+
+    let source = Arc::new(main_img.bytes);
+    let sources = vec![Arc::clone(&source).into()];
+    let analysis = run_processing(
+        &vips_app,
+        &admission,
+        &processing_pool,
+        sources,
+        move || image_processor::analyze_image(Arc::unwrap_or_clone(source), &config),
+    )
+    .await?;
+    let body = serde_json::to_string(&analysis).unwrap();
+    Ok(json_response(main_img.response_headers, body))
+}
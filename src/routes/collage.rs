@@ -0,0 +1,155 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderMap, Response, StatusCode},
+};
+use futures::future::join_all;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::{
+    commons::{errors::InvalidParameterError, CollageRequest, ImageFormat},
+    image_processor::{
+        self,
+        collage::{Grid, MAX_COLLAGE_DIMENSION, MAX_LAYOUT_TRACKS},
+    },
+    image_provider::ImageResponse,
+    routes::image::{
+        ensure_source_format_enabled, get_negotiable_formats, log_fetch_duration, log_size_metrics,
+        run_processing, DecodedSource, ImageProcessingError, ProcessImageRequestExtractor,
+    },
+    AppState,
+};
+
+// Composes several images into a single one laid out on a grid, e.g. the previews of a listing. The images are
+// downloaded concurrently and any failure fails the whole collage, as a missing cell would leave a hole in it.
+pub async fn compose_collage(
+    State(AppState {
+        vips_app,
+        admission,
+        processing_pool,
+        image_provider,
+        config,
+        ..
+    }): State<AppState>,
+    headers: HeaderMap,
+    ProcessImageRequestExtractor(mut params): ProcessImageRequestExtractor<CollageRequest>,
+) -> Result<Response<Body>, ImageProcessingError> {
+    params.encoder = params
+        .encoder
+        .with_defaults(config.encoder_defaults.as_ref());
+    params.encoder.validate()?;
+    let grid = get_grid(&params)?;
+    let spans: Vec<(u16, u16)> = params
+        .cells
+        .iter()
+        .map(|cell| (cell.row_span, cell.column_span))
+        .collect();
+    let areas = grid
+        .place(&spans)
+        .ok_or_else(|| InvalidParameterError::new("cells", "they don't fit in the layout"))?;
+
+    let now = SystemTime::now();
+    let images: Vec<ImageResponse> = join_all(
+        params
+            .cells
+            .iter()
+            .map(|cell| image_provider.get_file(&cell.image_address, &config)),
+    )
+    .await
+    .into_iter()
+    .collect::<Result<_, _>>()?;
+    for image in &images {
+        ensure_source_format_enabled(&image.bytes, &config)?;
+    }
+    log_fetch_duration(now);
+
+    let total_input_size = images.iter().map(|image| image.bytes.len()).sum();
+    let negotiate_format = matches!(params.format, ImageFormat::Auto);
+    let accepted_formats = if negotiate_format {
+        get_negotiable_formats(&headers, &config)
+    } else {
+        Vec::new()
+    };
+    let buffers: Vec<Arc<Vec<u8>>> = images
+        .into_iter()
+        .map(|image| Arc::new(image.bytes))
+        .collect();
+    let sources = buffers.iter().cloned().map(DecodedSource::from).collect();
+    let collage = run_processing(
+        &vips_app,
+        &admission,
+        &processing_pool,
+        sources,
+        move || {
+            let buffers = buffers.into_iter().map(Arc::unwrap_or_clone).collect();
+            image_processor::compose_collage(
+                buffers,
+                grid,
+                areas,
+                params,
+                accepted_formats,
+                &config,
+            )
+        },
+    )
+    .await?;
+    log_size_metrics(&collage.format, total_input_size, collage.bytes.len());
+
+    let mut response_builder = Response::builder().status(StatusCode::OK);
+    if negotiate_format {
+        response_builder = response_builder.header(header::VARY, "Accept");
+    }
+    if let Some(quality) = collage.quality {
+        response_builder = response_builder.header("X-Dali-Quality", quality);
+    }
+    Ok(response_builder
+        .header("Content-Type", format!("image/{}", collage.format))
+        .body(Body::from(collage.bytes))
+        .unwrap())
+}
+
+fn get_grid(params: &CollageRequest) -> Result<Grid, InvalidParameterError> {
+    let (Some(width), Some(height)) = (params.size.width, params.size.height) else {
+        return Err(InvalidParameterError::new(
+            "size",
+            "both the width and the height of the collage are required",
+        ));
+    };
+    if !(1..=MAX_COLLAGE_DIMENSION).contains(&width)
+        || !(1..=MAX_COLLAGE_DIMENSION).contains(&height)
+    {
+        return Err(InvalidParameterError::new(
+            "size",
+            &format!(
+                "{}x{} is not between 1 and {} on both sides",
+                width, height, MAX_COLLAGE_DIMENSION
+            ),
+        ));
+    }
+    let layout = &params.layout;
+    if !(1..=MAX_LAYOUT_TRACKS).contains(&layout.rows)
+        || !(1..=MAX_LAYOUT_TRACKS).contains(&layout.columns)
+    {
+        return Err(InvalidParameterError::new(
+            "layout",
+            &format!(
+                "the rows and the columns have to be between 1 and {}",
+                MAX_LAYOUT_TRACKS
+            ),
+        ));
+    }
+    if params.cells.is_empty() {
+        return Err(InvalidParameterError::new(
+            "cells",
+            "at least one cell is required",
+        ));
+    }
+    Ok(Grid {
+        width,
+        height,
+        rows: layout.rows,
+        columns: layout.columns,
+        gutter: i32::from(layout.gutter),
+    })
+}
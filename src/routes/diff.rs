@@ -0,0 +1,85 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Response, StatusCode},
+};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::{
+    commons::{DiffOutput, DiffRequest},
+    image_processor,
+    routes::image::{
+        ensure_source_format_enabled, log_fetch_duration, run_processing, ImageProcessingError,
+        ProcessImageRequestExtractor,
+    },
+    AppState,
+};
+
+// Compares two images, e.g. the outputs of two encoder settings or the photo of a listing before and after an edit.
+// The response isn't cached like the images as it depends on both of them.
+pub async fn diff_images(
+    State(AppState {
+        vips_app,
+        admission,
+        processing_pool,
+        image_provider,
+        config,
+        ..
+    }): State<AppState>,
+    ProcessImageRequestExtractor(params): ProcessImageRequestExtractor<DiffRequest>,
+) -> Result<Response<Body>, ImageProcessingError> {
+    let now = SystemTime::now();
+    let (first, second) = tokio::join!(
+        image_provider.get_file(&params.image_address, &config),
+        image_provider.get_file(&params.other_image_address, &config),
+    );
+    let (first, second) = (first?, second?);
+    ensure_source_format_enabled(&first.bytes, &config)?;
+    ensure_source_format_enabled(&second.bytes, &config)?;
+    log_fetch_duration(now);
+
+    let with_heatmap = params.output == DiffOutput::Heatmap;
+    let (first, second) = (Arc::new(first.bytes), Arc::new(second.bytes));
+    let sources = vec![Arc::clone(&first).into(), Arc::clone(&second).into()];
+    let (image_diff, heatmap) = run_processing(
+        &vips_app,
+        &admission,
+        &processing_pool,
+        sources,
+        move || {
+            image_processor::compare_images(
+                Arc::unwrap_or_clone(first),
+                Arc::unwrap_or_clone(second),
+                params.threshold,
+                with_heatmap,
+                &config,
+            )
+        },
+    )
+    .await?;
+
+    let response_builder = Response::builder().status(StatusCode::OK);
+    Ok(match heatmap {
+        Some(heatmap) => {
+            let mut response_builder = response_builder
+                .header("Content-Type", "image/png")
+                .header(
+                    "X-Dali-Mean-Absolute-Difference",
+                    image_diff.mean_absolute_difference.to_string(),
+                )
+                .header(
+                    "X-Dali-Changed-Percentage",
+                    image_diff.changed_percentage.to_string(),
+                );
+            if let Some(psnr) = image_diff.psnr {
+                response_builder = response_builder.header("X-Dali-Psnr", psnr.to_string());
+            }
+            response_builder.body(Body::from(heatmap)).unwrap()
+        }
+        None => response_builder
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&image_diff).unwrap()))
+            .unwrap(),
+    })
+}
@@ -1,26 +1,41 @@
 use axum::{
     body::Body,
     extract::{FromRequest, Request, State},
-    http::{Response, StatusCode},
+    http::{header, HeaderMap, Response, StatusCode},
     response::IntoResponse,
 };
 use core::str;
 use futures::future::join_all;
+use libvips::VipsApp;
 use log::{debug, error, warn};
+use rayon::ThreadPool;
 use serde::de::DeserializeOwned;
 use serde_json::json;
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 use thiserror::Error;
 
 use crate::{
-    commons::{ImageFormat, ProcessImageRequest},
-    image_processor,
-    routes::metric::FILES_EXCEEDING_MAX_ALLOWED_SIZE,
+    commons::{
+        config::Configuration, detect_source_format, errors::InvalidParameterError,
+        get_accepted_formats, timestamp_millis, ImageFormat, OutputMode, ProcessImageRequest,
+        Quality, SourceFormat,
+    },
+    image_processor::{
+        self, cancellation::CancellationToken, palette::MAX_PALETTE_SIZE,
+        placeholder::encode_base64, ProcessedImage, ProcessingError,
+    },
+    image_provider::ImageResponse,
+    routes::admission::{Admission, MemoryReservation},
+    routes::metric::{FILES_EXCEEDING_MAX_ALLOWED_SIZE, IMAGES_EXCEEDING_PIXEL_LIMITS},
     AppState,
 };
 
-use super::metric::{FETCH_DURATION, INPUT_SIZE, OUTPUT_SIZE};
+use super::metric::{
+    ABANDONED_PROCESSING_DURATION, AUTO_QUALITY, FETCH_DURATION, INPUT_SIZE, OUTPUT_SIZE,
+    PROCESSING_POOL_QUEUE_DEPTH,
+};
 
 // The following response headers are determined by Dali as it formats the image dowloaded from the provided source.
 // Thus the length and type of the resulted image might be different compared to what the storage engine has returned.
@@ -28,6 +43,20 @@ use super::metric::{FETCH_DURATION, INPUT_SIZE, OUTPUT_SIZE};
 // to lower the other ones that we compare with.
 const HEADERS_DETERMINED_BY_DALI: [&str; 2] = ["content-type", "content-length"];
 
+// The formats taken into account for `format=Auto` when none are configured through `auto_formats`.
+const DEFAULT_AUTO_FORMATS: [ImageFormat; 4] = [
+    ImageFormat::Avif,
+    ImageFormat::Webp,
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+];
+
+// Delay after which the clients are invited to retry the requests shed by the admission control.
+const RETRY_AFTER_SECONDS: u32 = 1;
+
+// Every rendition is resized and encoded on its own, so their amount is bounded like the rest of the work per request.
+const MAX_RENDITIONS: usize = 10;
+
 pub struct ProcessImageRequestExtractor<T>(pub T);
 
 impl<S, T> FromRequest<S> for ProcessImageRequestExtractor<T>
@@ -74,6 +103,47 @@ pub enum ImageProcessingError {
     LibvipsProcessingFailed(libvips::error::Error),
     #[error("the image exceeds the allowed size")]
     FileSizeExceeded(u32),
+    #[error("the format `{0}` of the image is not supported")]
+    UnsupportedSourceFormat(SourceFormat),
+    #[error("{0}")]
+    InvalidParameter(#[from] InvalidParameterError),
+    #[error("the image cannot be encoded within `{0}` bytes")]
+    TargetSizeUnreachable(u32),
+    #[error("the image of {1}x{2} pixels exceeds the `{0}` limit")]
+    PixelLimitExceeded(&'static str, i32, i32),
+    #[error("the request wasn't handled within its deadline")]
+    RequestDeadlineExceeded,
+    #[error("the image processing was cancelled")]
+    ProcessingCancelled,
+    #[error("the processing queue is full")]
+    ProcessingQueueFull,
+    #[error("the metadata cannot be removed from the `{0}` image")]
+    MetadataRemovalFailed(ImageFormat),
+}
+
+impl From<libvips::error::Error> for ImageProcessingError {
+    fn from(error: libvips::error::Error) -> Self {
+        ImageProcessingError::LibvipsProcessingFailed(error)
+    }
+}
+
+impl From<ProcessingError> for ImageProcessingError {
+    fn from(error: ProcessingError) -> Self {
+        match error {
+            ProcessingError::LibvipsFailed(e) => ImageProcessingError::LibvipsProcessingFailed(e),
+            ProcessingError::InvalidParameter(e) => ImageProcessingError::InvalidParameter(e),
+            ProcessingError::TargetSizeUnreachable(max_bytes) => {
+                ImageProcessingError::TargetSizeUnreachable(max_bytes)
+            }
+            ProcessingError::PixelLimitExceeded(limit, width, height) => {
+                ImageProcessingError::PixelLimitExceeded(limit, width, height)
+            }
+            ProcessingError::Cancelled => ImageProcessingError::ProcessingCancelled,
+            ProcessingError::MetadataRemovalFailed(format) => {
+                ImageProcessingError::MetadataRemovalFailed(format)
+            }
+        }
+    }
 }
 
 impl IntoResponse for ImageProcessingError {
@@ -83,6 +153,7 @@ impl IntoResponse for ImageProcessingError {
             self
         );
 
+        let retry_after = matches!(self, ImageProcessingError::ProcessingQueueFull);
         let (status, message) = match self {
             ImageProcessingError::ClientReturnedErrorStatusCode(status, resource) => (
                 StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_REQUEST),
@@ -106,35 +177,168 @@ impl IntoResponse for ImageProcessingError {
                 StatusCode::BAD_REQUEST,
                 format!("The image exceeds the allowed size of {max_allowed_size} bytes. Please ensure the file size is within the permissible limit or adjust the configuration."),
             )},
+            ImageProcessingError::UnsupportedSourceFormat(source_format) => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("Processing images of the format '{}' is not enabled.", source_format),
+            ),
+            ImageProcessingError::InvalidParameter(e) => (
+                StatusCode::BAD_REQUEST,
+                e.to_string(),
+            ),
+            ImageProcessingError::TargetSizeUnreachable(max_bytes) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("The image cannot be encoded within {max_bytes} bytes with the configured minimum quality and scale."),
+            ),
+            ImageProcessingError::PixelLimitExceeded(limit, width, height) => {
+                IMAGES_EXCEEDING_PIXEL_LIMITS.inc();
+                (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("The image of {width}x{height} pixels exceeds the configured `{limit}`."),
+            )},
+            ImageProcessingError::RequestDeadlineExceeded => (
+                StatusCode::GATEWAY_TIMEOUT,
+                String::from("The image couldn't be processed within the allowed time."),
+            ),
+            ImageProcessingError::ProcessingCancelled => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                String::from("The processing of the image was cancelled."),
+            ),
+            ImageProcessingError::ProcessingQueueFull => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                String::from("Too many images are being processed, please retry later."),
+            ),
             _ => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 String::from("Something went wrong on our side."),
             ),
         };
         let body = json!({ "error": message }).to_string();
-        Response::builder()
+        let mut response_builder = Response::builder()
             .status(status)
-            .header("Content-Type", "application/json")
-            .body(body.into())
-            .unwrap()
+            .header("Content-Type", "application/json");
+        if retry_after {
+            response_builder = response_builder.header(header::RETRY_AFTER, RETRY_AFTER_SECONDS);
+        }
+        response_builder.body(body.into()).unwrap()
     }
 }
 
 pub async fn process_image(
     State(AppState {
         vips_app,
+        admission,
+        processing_pool,
         image_provider,
         config,
         watermark_cache,
     }): State<AppState>,
-    ProcessImageRequestExtractor(params): ProcessImageRequestExtractor<ProcessImageRequest>,
+    headers: HeaderMap,
+    ProcessImageRequestExtractor(mut params): ProcessImageRequestExtractor<ProcessImageRequest>,
 ) -> Result<Response<Body>, ImageProcessingError> {
+    // the defaults are merged by the processing, which tells the settings of the request apart for the passthrough
+    params
+        .encoder
+        .clone()
+        .with_defaults(config.encoder_defaults.as_ref())
+        .validate()?;
+    params.keep_metadata = params.keep_metadata.or(config.keep_metadata);
+    if let Some(threshold) = params
+        .trim
+        .as_ref()
+        .map(|trim| trim.threshold)
+        .filter(|threshold| threshold.is_nan() || *threshold < 0.0)
+    {
+        return Err(InvalidParameterError::new(
+            "trim[threshold]",
+            &format!("{} is not a positive number", threshold),
+        )
+        .into());
+    }
+    if let Some(palette_size) = params
+        .palette_size
+        .filter(|size| !(1..=MAX_PALETTE_SIZE).contains(size))
+    {
+        return Err(InvalidParameterError::new(
+            "palette_size",
+            &format!("{} is not between 1 and {}", palette_size, MAX_PALETTE_SIZE),
+        )
+        .into());
+    }
+    if params.renditions.len() > MAX_RENDITIONS {
+        return Err(InvalidParameterError::new(
+            "renditions",
+            &format!("at most {} renditions can be requested", MAX_RENDITIONS),
+        )
+        .into());
+    }
+    let has_renditions = !params.renditions.is_empty();
+    match params.output {
+        OutputMode::Manifest if !has_renditions => {
+            return Err(InvalidParameterError::new(
+                "output",
+                "Manifest requires at least one rendition",
+            )
+            .into());
+        }
+        OutputMode::Image | OutputMode::Manifest => {}
+        _ if has_renditions => {
+            return Err(InvalidParameterError::new(
+                "renditions",
+                "only the Image and Manifest outputs can have renditions",
+            )
+            .into());
+        }
+        _ => {}
+    }
+
     let now = SystemTime::now();
     let main_img = image_provider
         .get_file(&params.image_address, &config)
         .await?;
     let mut total_input_size = main_img.bytes.len();
 
+    ensure_source_format_enabled(&main_img.bytes, &config)?;
+
+    match params.output {
+        OutputMode::Image | OutputMode::Manifest => {}
+        OutputMode::Palette => {
+            log_fetch_duration(now);
+            return get_palette(
+                &vips_app,
+                &admission,
+                &processing_pool,
+                main_img,
+                params,
+                config,
+            )
+            .await;
+        }
+        OutputMode::Blurhash | OutputMode::Thumbhash => {
+            log_fetch_duration(now);
+            return get_placeholder(
+                &vips_app,
+                &admission,
+                &processing_pool,
+                main_img,
+                params,
+                config,
+            )
+            .await;
+        }
+        OutputMode::Phash => {
+            log_fetch_duration(now);
+            return get_perceptual_hash(
+                &vips_app,
+                &admission,
+                &processing_pool,
+                main_img,
+                params,
+                config,
+            )
+            .await;
+        }
+    }
+
     let watermarks_futures = params.watermarks.iter().map(|wm| {
         let cache = watermark_cache.clone();
         let provider = image_provider.clone();
@@ -174,66 +378,531 @@ pub async fn process_image(
         })
         .collect();
 
-    if let Ok(elapsed) = now.elapsed() {
-        let duration =
-            (elapsed.as_secs() as f64) + f64::from(elapsed.subsec_nanos()) / 1_000_000_000_f64;
-        FETCH_DURATION.success.observe(duration);
+    log_fetch_duration(now);
+
+    let negotiate_format = if !has_renditions {
+        matches!(params.format, ImageFormat::Auto)
+    } else {
+        params
+            .renditions
+            .iter()
+            .any(|rendition| matches!(rendition.format.unwrap_or(params.format), ImageFormat::Auto))
+    };
+    let accepted_formats = if negotiate_format {
+        get_negotiable_formats(&headers, &config)
+    } else {
+        Vec::new()
+    };
+
+    let source = Arc::new(main_img.bytes);
+    // the watermarks are decoded along with the source
+    let sources = std::iter::once(DecodedSource {
+        buffer: Arc::clone(&source),
+        page: params.page,
+        density: params.density,
+    })
+    .chain(watermarks.iter().cloned().map(DecodedSource::from))
+    .collect();
+
+    if has_renditions {
+        let output = params.output;
+        let processing_config = config.clone();
+        let renditions = run_processing(
+            &vips_app,
+            &admission,
+            &processing_pool,
+            sources,
+            move || {
+                image_processor::process_renditions(
+                    Arc::unwrap_or_clone(source),
+                    watermarks,
+                    params,
+                    accepted_formats,
+                    &processing_config,
+                )
+            },
+        )
+        .await?;
+        // the source is accounted for once, under the format of the first rendition
+        log_input_size_metrics(&renditions[0].format, total_input_size);
+        for rendition in &renditions {
+            log_output_size_metrics(&rendition.format, rendition.bytes.len());
+        }
+        let mut response_builder = forward_upstream_headers(main_img.response_headers);
+        if negotiate_format {
+            response_builder = response_builder.header(header::VARY, "Accept");
+        }
+        if let Some(trim_box) = &renditions[0].trim_box {
+            response_builder = response_builder.header("X-Dali-Trim", trim_box.to_string());
+        }
+        return Ok(match output {
+            OutputMode::Manifest => response_builder
+                .header("Content-Type", "application/json")
+                .body(Body::from(get_renditions_manifest(&renditions)))
+                .unwrap(),
+            _ => {
+                let boundary = get_multipart_boundary(&renditions);
+                response_builder
+                    .header(
+                        "Content-Type",
+                        format!("multipart/mixed; boundary={}", boundary),
+                    )
+                    .body(Body::from(get_renditions_multipart(&renditions, &boundary)))
+                    .unwrap()
+            }
+        });
+    }
+
+    let auto_quality = matches!(params.quality, Some(Quality::Auto(_)));
+    let processing_config = config.clone();
+    let processed_image = run_processing(
+        &vips_app,
+        &admission,
+        &processing_pool,
+        sources,
+        move || {
+            image_processor::process_image(
+                Arc::unwrap_or_clone(source),
+                watermarks,
+                params,
+                accepted_formats,
+                &processing_config,
+            )
+        },
+    )
+    .await?;
+
+    let format = processed_image.format;
+    log_size_metrics(&format, total_input_size, processed_image.bytes.len());
+    if let Some(quality) = processed_image.quality.filter(|_| auto_quality) {
+        log_auto_quality_metrics(&format, quality);
+    }
+
+    let mut response_builder = forward_upstream_headers(main_img.response_headers);
+    if negotiate_format {
+        // the same url serves different formats depending on the client, caches have to take it into account
+        response_builder = response_builder.header(header::VARY, "Accept");
+    }
+    if let Some(quality) = processed_image.quality {
+        response_builder = response_builder.header("X-Dali-Quality", quality);
+    }
+    if let Some(trim_box) = processed_image.trim_box {
+        response_builder = response_builder.header("X-Dali-Trim", trim_box.to_string());
+    }
+    if processed_image.passthrough {
+        response_builder = response_builder.header("X-Dali-Passthrough", "true");
+    }
+
+    Ok(response_builder
+        .header("Content-Type", format!("image/{}", format))
+        .body(Body::from(processed_image.bytes))
+        .unwrap())
+}
+
+// The enabled formats the client accepts, in the order of preference of `format=Auto`.
+pub fn get_negotiable_formats(headers: &HeaderMap, config: &Configuration) -> Vec<ImageFormat> {
+    get_accepted_formats(
+        headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok()),
+        config
+            .auto_formats
+            .as_deref()
+            .unwrap_or(&DEFAULT_AUTO_FORMATS),
+    )
+}
+
+pub fn ensure_source_format_enabled(
+    buffer: &[u8],
+    config: &Configuration,
+) -> Result<(), ImageProcessingError> {
+    // rendering PDFs is considerably more expensive than decoding the regular formats, so it's opt-in. The flag only
+    // gates the sources, they are rendered by the PDF loader libvips was built with
+    if detect_source_format(buffer) == SourceFormat::Pdf
+        && !config.pdf_loader_enabled.unwrap_or(false)
+    {
+        return Err(ImageProcessingError::UnsupportedSourceFormat(
+            SourceFormat::Pdf,
+        ));
+    }
+    Ok(())
+}
+
+async fn get_placeholder(
+    vips_app: &VipsApp,
+    admission: &Admission,
+    processing_pool: &ThreadPool,
+    main_img: ImageResponse,
+    params: ProcessImageRequest,
+    config: Arc<Configuration>,
+) -> Result<Response<Body>, ImageProcessingError> {
+    let output = params.output;
+    let source = Arc::new(main_img.bytes);
+    let sources = vec![DecodedSource {
+        buffer: Arc::clone(&source),
+        page: params.page,
+        density: params.density,
+    }];
+    let placeholder = run_processing(vips_app, admission, processing_pool, sources, move || {
+        image_processor::compute_placeholder(Arc::unwrap_or_clone(source), params, &config)
+    })
+    .await?;
+    let hash_key = match output {
+        OutputMode::Thumbhash => "thumbhash",
+        _ => "blurhash",
+    };
+    let body = json!({
+        hash_key: placeholder.hash,
+        "width": placeholder.width,
+        "height": placeholder.height,
+    })
+    .to_string();
+    Ok(json_response(main_img.response_headers, body))
+}
+
+async fn get_palette(
+    vips_app: &VipsApp,
+    admission: &Admission,
+    processing_pool: &ThreadPool,
+    main_img: ImageResponse,
+    params: ProcessImageRequest,
+    config: Arc<Configuration>,
+) -> Result<Response<Body>, ImageProcessingError> {
+    let source = Arc::new(main_img.bytes);
+    let sources = vec![DecodedSource {
+        buffer: Arc::clone(&source),
+        page: params.page,
+        density: params.density,
+    }];
+    let palette = run_processing(vips_app, admission, processing_pool, sources, move || {
+        image_processor::compute_palette(Arc::unwrap_or_clone(source), params, &config)
+    })
+    .await?;
+    let body = json!({
+        "dominant": palette.first().map(|entry| entry.colour.to_string()),
+        "palette": palette
+            .iter()
+            .map(|entry| json!({ "colour": entry.colour.to_string(), "share": entry.share }))
+            .collect::<Vec<_>>(),
+    })
+    .to_string();
+    Ok(json_response(main_img.response_headers, body))
+}
+
+async fn get_perceptual_hash(
+    vips_app: &VipsApp,
+    admission: &Admission,
+    processing_pool: &ThreadPool,
+    main_img: ImageResponse,
+    params: ProcessImageRequest,
+    config: Arc<Configuration>,
+) -> Result<Response<Body>, ImageProcessingError> {
+    let algorithm = params.hash_algorithm;
+    let source = Arc::new(main_img.bytes);
+    let sources = vec![DecodedSource {
+        buffer: Arc::clone(&source),
+        page: params.page,
+        density: params.density,
+    }];
+    let hash = run_processing(vips_app, admission, processing_pool, sources, move || {
+        image_processor::compute_perceptual_hash(Arc::unwrap_or_clone(source), params, &config)
+    })
+    .await?;
+    let body = json!({ "algorithm": algorithm, "hash": format!("{:016x}", hash) }).to_string();
+    Ok(json_response(main_img.response_headers, body))
+}
+
+// The renditions in the order of the request, along with their dimensions and, when determined by Dali, quality.
+fn get_renditions_manifest(renditions: &[ProcessedImage]) -> String {
+    json!({
+        "renditions": renditions
+            .iter()
+            .map(|rendition| {
+                json!({
+                    "format": rendition.format.to_string(),
+                    "width": rendition.width,
+                    "height": rendition.height,
+                    "quality": rendition.quality,
+                    "content_length": rendition.bytes.len(),
+                    "data": encode_base64(&rendition.bytes),
+                })
+            })
+            .collect::<Vec<_>>(),
+    })
+    .to_string()
+}
+
+// One part per rendition, in the order of the request, each one carrying its own headers.
+fn get_renditions_multipart(renditions: &[ProcessedImage], boundary: &str) -> Vec<u8> {
+    let mut body = Vec::with_capacity(
+        renditions
+            .iter()
+            .map(|rendition| rendition.bytes.len())
+            .sum(),
+    );
+    for (index, rendition) in renditions.iter().enumerate() {
+        let mut headers = format!(
+            "--{}\r\nContent-Type: image/{}\r\nContent-Length: {}\r\nX-Dali-Rendition: {}\r\n\
+             X-Dali-Width: {}\r\nX-Dali-Height: {}\r\n",
+            boundary,
+            rendition.format,
+            rendition.bytes.len(),
+            index,
+            rendition.width,
+            rendition.height
+        );
+        if let Some(quality) = rendition.quality {
+            headers.push_str(&format!("X-Dali-Quality: {}\r\n", quality));
+        }
+        headers.push_str("\r\n");
+        body.extend_from_slice(headers.as_bytes());
+        body.extend_from_slice(&rendition.bytes);
+        body.extend_from_slice(b"\r\n");
     }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
 
-    let format = params.format;
+// A boundary that none of the images contains, as the parts would be cut short otherwise.
+fn get_multipart_boundary(renditions: &[ProcessedImage]) -> String {
+    let prefix = format!("dali-renditions-{:x}", timestamp_millis());
+    (0..)
+        .map(|attempt| format!("{}-{}", prefix, attempt))
+        .find(|boundary| {
+            !renditions.iter().any(|rendition| {
+                rendition
+                    .bytes
+                    .windows(boundary.len())
+                    .any(|window| window == boundary.as_bytes())
+            })
+        })
+        .unwrap()
+}
 
-    // processing the image is a blocking operation and originally I've use the tokio::spawn_blocking option to process the image.
-    // it was decently performing, but I've benchmarked rayon as well and the performance improved a lot in terms of
-    // response time and memory used
+// JSON documents describing the image are cached the same way as the image itself.
+pub fn json_response(response_headers: HashMap<String, Vec<u8>>, body: String) -> Response<Body> {
+    forward_upstream_headers(response_headers)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+// processing the image is a blocking operation and originally I've use the tokio::spawn_blocking option to process the image.
+// it was decently performing, but I've benchmarked rayon as well and the performance improved a lot in terms of
+// response time and memory used
+pub async fn run_processing<T, F>(
+    vips_app: &VipsApp,
+    admission: &Admission,
+    processing_pool: &ThreadPool,
+    sources: Vec<DecodedSource>,
+    process: F,
+) -> Result<T, ImageProcessingError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, ProcessingError> + Send + 'static,
+{
+    let permit = admission.admit().await?;
+    let memory_reservation = reserve_memory(admission, processing_pool, sources)
+        .await
+        .inspect_err(|e| log_processing_error(vips_app, e))?;
+    let token = CancellationToken::default();
+    let worker_token = token.clone();
+    // dropped along with this future when the request is abandoned, which stops the processing at its next stage
+    let _cancel_on_drop = token.cancel_on_drop();
+    spawn_on_pool(processing_pool, move || {
+        let _permit = permit;
+        let _memory_reservation = memory_reservation;
+        worker_token.run(process)
+    })
+    .await?
+    .map_err(ImageProcessingError::from)
+    .inspect_err(|e| log_processing_error(vips_app, e))
+}
+
+// A buffer the job decodes, along with the page and the density it is decoded at. The buffers decoded with the loader
+// defaults, e.g. the watermarks, are converted from their bytes.
+pub struct DecodedSource {
+    pub buffer: Arc<Vec<u8>>,
+    pub page: Option<u16>,
+    pub density: Option<u16>,
+}
+
+impl From<Arc<Vec<u8>>> for DecodedSource {
+    fn from(buffer: Arc<Vec<u8>>) -> Self {
+        DecodedSource {
+            buffer,
+            page: None,
+            density: None,
+        }
+    }
+}
+
+// The sources and watermarks of the job wait until they fit within the memory budget once decoded. Their headers are
+// read on the processing pool as well.
+async fn reserve_memory(
+    admission: &Admission,
+    processing_pool: &ThreadPool,
+    sources: Vec<DecodedSource>,
+) -> Result<Option<MemoryReservation>, ImageProcessingError> {
+    if !admission.has_memory_budget() {
+        return Ok(None);
+    }
+    let estimated_memory = spawn_on_pool(processing_pool, move || {
+        sources
+            .iter()
+            .map(|source| {
+                image_processor::estimate_decoded_size(&source.buffer, source.page, source.density)
+            })
+            .sum::<Result<u64, ProcessingError>>()
+    })
+    .await??;
+    Ok(Some(admission.reserve_memory(estimated_memory).await))
+}
+
+async fn spawn_on_pool<T, F>(
+    processing_pool: &ThreadPool,
+    job: F,
+) -> Result<T, ImageProcessingError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
     let (send, recv) = tokio::sync::oneshot::channel();
-    rayon::spawn(move || {
-        let image = image_processor::process_image(main_img.bytes, watermarks, params);
-        let _ = send.send(image);
+    PROCESSING_POOL_QUEUE_DEPTH.inc();
+    processing_pool.spawn(move || {
+        PROCESSING_POOL_QUEUE_DEPTH.dec();
+        let start = Instant::now();
+        let result = job();
+        // nobody waits for the result anymore, the time spent on it was wasted
+        if send.send(result).is_err() {
+            ABANDONED_PROCESSING_DURATION.observe(start.elapsed().as_secs_f64());
+        }
     });
-    let processed_image = recv.await.map_err(|e| {
-        let error_message = format!("failed to join the thread which process the image. error: {}", e);
+    recv.await.map_err(|e| {
+        let error_message = format!(
+            "failed to join the thread which process the image. error: {}",
+            e
+        );
         error!("{}", error_message);
         ImageProcessingError::ProcessingWorkerJoinError
-    })?
-    .map_err(|e| {
+    })
+}
+
+fn log_processing_error(vips_app: &VipsApp, error: &ImageProcessingError) {
+    if let ImageProcessingError::LibvipsProcessingFailed(e) = error {
         let error_message = format!("the image processing has failed for the resource with the error: {}. libvips raw error is: {}",
             e, vips_app.error_buffer().unwrap_or("").replace("\n", ". "));
         error!("{}", error_message);
-        ImageProcessingError::LibvipsProcessingFailed(e)
-    })?;
-
-    log_size_metrics(&format, total_input_size, processed_image.len());
+    }
+}
 
+fn forward_upstream_headers(
+    response_headers: HashMap<String, Vec<u8>>,
+) -> axum::http::response::Builder {
     let mut response_builder = Response::builder().status(StatusCode::OK);
-    for (key, value) in main_img.response_headers.into_iter() {
+    for (key, value) in response_headers.into_iter() {
         if !HEADERS_DETERMINED_BY_DALI.contains(&key.to_lowercase().as_str()) {
             response_builder = response_builder.header(key, value);
         }
     }
+    response_builder
+}
 
-    Ok(response_builder
-        .header("Content-Type", format!("image/{}", format))
-        .body(Body::from(processed_image))
-        .unwrap())
+pub fn log_fetch_duration(start: SystemTime) {
+    if let Ok(elapsed) = start.elapsed() {
+        let duration =
+            (elapsed.as_secs() as f64) + f64::from(elapsed.subsec_nanos()) / 1_000_000_000_f64;
+        FETCH_DURATION.success.observe(duration);
+    }
+}
+
+pub fn log_size_metrics(format: &ImageFormat, input_size: usize, response_length: usize) {
+    log_input_size_metrics(format, input_size);
+    log_output_size_metrics(format, response_length);
 }
 
-fn log_size_metrics(format: &ImageFormat, input_size: usize, response_length: usize) {
+fn log_input_size_metrics(format: &ImageFormat, input_size: usize) {
+    let input_size = input_size as f64;
     match format {
-        ImageFormat::Jpeg => {
-            INPUT_SIZE.jpeg.observe(input_size as f64);
-            OUTPUT_SIZE.jpeg.observe(response_length as f64);
-        }
-        ImageFormat::Heic => {
-            INPUT_SIZE.heic.observe(input_size as f64);
-            OUTPUT_SIZE.heic.observe(response_length as f64);
-        }
-        ImageFormat::Webp => {
-            INPUT_SIZE.webp.observe(input_size as f64);
-            OUTPUT_SIZE.webp.observe(response_length as f64);
-        }
-        ImageFormat::Png => {
-            INPUT_SIZE.png.observe(input_size as f64);
-            OUTPUT_SIZE.png.observe(response_length as f64);
-        }
+        ImageFormat::Jpeg | ImageFormat::Auto => INPUT_SIZE.jpeg.observe(input_size),
+        ImageFormat::Heic => INPUT_SIZE.heic.observe(input_size),
+        ImageFormat::Webp => INPUT_SIZE.webp.observe(input_size),
+        ImageFormat::Png => INPUT_SIZE.png.observe(input_size),
+        ImageFormat::Avif => INPUT_SIZE.avif.observe(input_size),
+    }
+}
+
+fn log_output_size_metrics(format: &ImageFormat, response_length: usize) {
+    let response_length = response_length as f64;
+    match format {
+        ImageFormat::Jpeg | ImageFormat::Auto => OUTPUT_SIZE.jpeg.observe(response_length),
+        ImageFormat::Heic => OUTPUT_SIZE.heic.observe(response_length),
+        ImageFormat::Webp => OUTPUT_SIZE.webp.observe(response_length),
+        ImageFormat::Png => OUTPUT_SIZE.png.observe(response_length),
+        ImageFormat::Avif => OUTPUT_SIZE.avif.observe(response_length),
+    }
+}
+
+fn log_auto_quality_metrics(format: &ImageFormat, quality: i32) {
+    let quality = f64::from(quality);
+    match format {
+        ImageFormat::Jpeg | ImageFormat::Auto => AUTO_QUALITY.jpeg.observe(quality),
+        ImageFormat::Heic => AUTO_QUALITY.heic.observe(quality),
+        ImageFormat::Webp => AUTO_QUALITY.webp.observe(quality),
+        ImageFormat::Png => AUTO_QUALITY.png.observe(quality),
+        ImageFormat::Avif => AUTO_QUALITY.avif.observe(quality),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_processor::cancellation;
+    use lazy_static::lazy_static;
+    use rayon::ThreadPoolBuilder;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    lazy_static! {
+        static ref VIPS_APP: VipsApp =
+            VipsApp::new("dali tests", false).expect("Can't initialize Vips");
+    }
+
+    const STAGES: u32 = 1000;
+
+    #[tokio::test]
+    async fn test_abandoned_processing_stops_early() {
+        let admission = Admission::new(&Configuration::for_tests(json!({})));
+        let processing_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let abandoned = ABANDONED_PROCESSING_DURATION.get_sample_count();
+        let (completed_stages, stages) = mpsc::channel();
+        let processing = run_processing(
+            &VIPS_APP,
+            &admission,
+            &processing_pool,
+            Vec::new(),
+            move || {
+                let mut stage = 0;
+                let result = loop {
+                    if let Err(e) = cancellation::ensure_not_cancelled() {
+                        break Err(e);
+                    }
+                    stage += 1;
+                    if stage == STAGES {
+                        break Ok(());
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                };
+                completed_stages.send(stage).unwrap();
+                result
+            },
+        );
+
+        let result = tokio::time::timeout(Duration::from_millis(100), processing).await;
+        assert!(result.is_err());
+        assert!(stages.recv_timeout(Duration::from_secs(5)).unwrap() < STAGES);
+        // the single thread of the pool is only available once the abandoned processing is over
+        processing_pool.install(|| ());
+        assert!(ABANDONED_PROCESSING_DURATION.get_sample_count() > abandoned);
     }
 }
@@ -0,0 +1,45 @@
+use axum::{body::Body, extract::State, http::Response};
+use std::time::SystemTime;
+
+use crate::{
+    commons::SourceImageRequest,
+    image_processor,
+    routes::image::{
+        ensure_source_format_enabled, json_response, log_fetch_duration, run_processing,
+        ImageProcessingError, ProcessImageRequestExtractor,
+    },
+    AppState,
+};
+
+// Describes the source image from its header and EXIF data, sparing the clients to download it just for that.
+pub async fn get_info(
+    State(AppState {
+        vips_app,
+        admission,
+        processing_pool,
+        image_provider,
+        config,
+        ..
+    }): State<AppState>,
+    ProcessImageRequestExtractor(params): ProcessImageRequestExtractor<SourceImageRequest>,
+) -> Result<Response<Body>, ImageProcessingError> {
+    let now = SystemTime::now();
+    let main_img = image_provider
+        .get_file(&params.image_address, &config)
+        .await?;
+    ensure_source_format_enabled(&main_img.bytes, &config)?;
+    log_fetch_duration(now);
+
+    let source = main_img.bytes;
+    // only the header and the metadata are read, no memory is reserved for the pixels
+    let info = run_processing(
+        &vips_app,
+        &admission,
+        &processing_pool,
+        Vec::new(),
+        move || image_processor::inspect_image(&source),
+    )
+    .await?;
+    let body = serde_json::to_string(&info).unwrap();
+    Ok(json_response(main_img.response_headers, body))
+}
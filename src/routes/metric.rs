@@ -6,7 +6,8 @@ use axum::{
 use lazy_static::lazy_static;
 use log::error;
 use prometheus::{
-    register_histogram_vec, register_int_counter, Encoder, HistogramVec, IntCounter, TextEncoder,
+    register_histogram, register_histogram_vec, register_int_counter, register_int_gauge, Encoder,
+    Histogram, HistogramVec, IntCounter, IntGauge, TextEncoder,
 };
 use prometheus_static_metric::make_static_metric;
 
@@ -29,6 +30,7 @@ make_static_metric! {
             png,
             webp,
             heic,
+            avif,
         }
     }
     pub struct OutputSize: Histogram {
@@ -37,6 +39,16 @@ make_static_metric! {
             png,
             webp,
             heic,
+            avif,
+        }
+    }
+    pub struct AutoQuality: Histogram {
+        "format" => {
+            jpeg,
+            png,
+            webp,
+            heic,
+            avif,
         }
     }
 }
@@ -66,17 +78,55 @@ lazy_static! {
         &["format"]
     )
     .expect("Cannot register metric");
+    pub static ref AUTO_QUALITY_VEC: HistogramVec = register_histogram_vec!(
+        "dali_auto_quality",
+        "Quality picked for the images requested with an automatic quality",
+        &["format"],
+        prometheus::linear_buckets(5.0, 10.0, 10).expect("Cannot create buckets")
+    )
+    .expect("Cannot register metric");
     pub static ref FILES_EXCEEDING_MAX_ALLOWED_SIZE: IntCounter = register_int_counter!(
         "dali_files_exceeding_max_allowed_size",
         "Amount of files that were not processed due to exceeding the maximum allowed size"
     )
     .expect("Cannot register metric");
+    pub static ref IMAGES_EXCEEDING_PIXEL_LIMITS: IntCounter = register_int_counter!(
+        "dali_images_exceeding_pixel_limits",
+        "Amount of images that were not processed due to exceeding the maximum allowed dimensions"
+    )
+    .expect("Cannot register metric");
+    pub static ref ABANDONED_PROCESSING_DURATION: Histogram = register_histogram!(
+        "dali_abandoned_processing_duration",
+        "Duration of the processing whose result was no longer awaited, e.g. past the request deadline"
+    )
+    .expect("Cannot register metric");
+    pub static ref PROCESSING_JOBS_IN_FLIGHT: IntGauge = register_int_gauge!(
+        "dali_processing_jobs_in_flight",
+        "Number of processing jobs currently running"
+    )
+    .expect("Cannot register metric");
+    pub static ref PROCESSING_JOBS_QUEUED: IntGauge = register_int_gauge!(
+        "dali_processing_jobs_queued",
+        "Number of processing jobs waiting for their turn to run"
+    )
+    .expect("Cannot register metric");
+    pub static ref PROCESSING_POOL_QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "dali_processing_pool_queue_depth",
+        "Number of processing jobs waiting for a thread of the processing pool"
+    )
+    .expect("Cannot register metric");
+    pub static ref MEMORY_BUDGET_WAIT_DURATION: Histogram = register_histogram!(
+        "dali_memory_budget_wait_duration",
+        "Duration of the wait for the processing memory budget to fit the decoded image"
+    )
+    .expect("Cannot register metric");
     pub static ref HTTP_DURATION: HttpRequestDuration =
         HttpRequestDuration::from(&HTTP_DURATION_VEC);
     pub static ref FETCH_DURATION: FetchRequestDuration =
         FetchRequestDuration::from(&FETCH_DURATION_VEC);
     pub static ref INPUT_SIZE: InputSize = InputSize::from(&INPUT_SIZE_VEC);
     pub static ref OUTPUT_SIZE: OutputSize = OutputSize::from(&OUTPUT_SIZE_VEC);
+    pub static ref AUTO_QUALITY: AutoQuality = AutoQuality::from(&AUTO_QUALITY_VEC);
 }
 
 pub async fn handle_prometheus_scrapping() -> impl IntoResponse {
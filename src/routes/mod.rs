@@ -1,2 +1,7 @@
+pub mod admission;
+pub mod analyze;
+pub mod collage;
+pub mod diff;
 pub mod metric;
-pub mod image;
\ No newline at end of file
+pub mod image;
+pub mod info;
\ No newline at end of file